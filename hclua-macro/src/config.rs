@@ -2,18 +2,25 @@ use std::default::Default;
 
 use quote::ToTokens;
 use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
 use syn::{self, parenthesized, Token};
 
 pub struct Config {
     pub name: String,
     pub light: bool,
+    /// std traits mapped to metamethods, see `OPS`
+    pub ops: Vec<syn::Ident>,
 }
 
 enum ConfigAttrib {
     Name(String),
     Light,
+    Ops(Vec<syn::Ident>),
 }
 
+/// names accepted by `hclua_cfg(ops(..))`, each calls `UserDataMetaMethods::derive_<name>`
+const OPS: &[&str] = &["add", "sub", "mul", "div", "rem", "neg", "eq", "ord", "display"];
+
 const CONFIG_ATTRIBUTE_NAME: &'static str = "hclua_cfg";
 
 impl Config {
@@ -37,13 +44,14 @@ impl Config {
         }
 
         let mut config: Config = Config {
-            name, light: false
+            name, light: false, ops: Vec::new()
         };
 
         for parsed_attrib in parsed_attributes {
             match parsed_attrib {
                 ConfigAttrib::Name(val) => config.name = val,
                 ConfigAttrib::Light => config.light = true,
+                ConfigAttrib::Ops(ops) => config.ops.extend(ops),
             }
         }
 
@@ -56,6 +64,7 @@ impl Default for Config {
         Config {
             name: String::new(),
             light: false,
+            ops: Vec::new(),
         }
     }
 }
@@ -73,6 +82,19 @@ impl Parse for ConfigAttrib {
                 let obj_name = content.parse::<syn::Ident>()?;
                 Ok(ConfigAttrib::Name(obj_name.to_string()))
             },
+            "ops" => {
+                let inner;
+                parenthesized!(inner in content);
+                let ops = Punctuated::<syn::Ident, Token![,]>::parse_terminated(&inner)?;
+                for op in ops.iter() {
+                    if !OPS.contains(&&op.to_string()[..]) {
+                        return Err(syn::parse::Error::new(
+                            op.span(), format!("unrecognized op '{}', expected one of {:?}", op, OPS)
+                        ));
+                    }
+                }
+                Ok(ConfigAttrib::Ops(ops.into_iter().collect()))
+            },
             _ => Err(syn::parse::Error::new(
                 name.span(), format!("unrecognized config option '{}'", name.to_string())
             ))
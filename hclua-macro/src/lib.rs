@@ -6,7 +6,15 @@ use syn::{self, parse_macro_input, ItemFn, ItemStruct, LitStr, Result};
 
 mod config;
 
-#[proc_macro_derive(ObjectMacro, attributes(hclua_skip, hclua_cfg))]
+/// whether the field is left out of the Lua fields, `hclua_parent` fields are reached by inheritance
+fn is_skipped(field: &syn::Field) -> bool {
+    field
+        .attrs
+        .iter()
+        .any(|attr| attr.path().is_ident("hclua_skip") || attr.path().is_ident("hclua_parent"))
+}
+
+#[proc_macro_derive(ObjectMacro, attributes(hclua_skip, hclua_cfg, hclua_parent))]
 pub fn object_macro_derive(input: TokenStream) -> TokenStream {
     let ItemStruct {
         ident,
@@ -19,11 +27,7 @@ pub fn object_macro_derive(input: TokenStream) -> TokenStream {
         .iter()
         .map(|field| {
             let field_ident = field.ident.clone().unwrap();
-            if field
-                .attrs
-                .iter()
-                .all(|attr| !attr.path().is_ident("hclua_skip"))
-            {
+            if !is_skipped(field) {
                 let get_name = format_ident!("get_{}", field_ident);
                 let set_name = format_ident!("set_{}", field_ident);
                 let ty = field.ty.clone();
@@ -42,26 +46,34 @@ pub fn object_macro_derive(input: TokenStream) -> TokenStream {
         })
         .collect();
 
-    let mut registers: Vec<_> = fields.iter().map(|field| {
+    let registers: Vec<_> = fields.iter().map(|field| {
         let field_ident = field.ident.clone().unwrap();
-        if field.attrs.iter().all(|attr| !attr.path().is_ident("hclua_skip")) {
+        if !is_skipped(field) {
             let ty = field.ty.clone();
-            let get_name = format_ident!("get_{}", field_ident);
-            let set_name = format_ident!("set_{}", field_ident);
             quote!{
-                hclua::LuaObject::<#ident>::add_object_method_get(lua, &stringify!(#field_ident), hclua::function1(|obj: &mut #ident| -> &#ty {
+                fields.add_field_ref::<#ty>(stringify!(#field_ident), |obj: &#ident| -> &#ty {
                     &obj.#field_ident
-                }));
-                hclua::LuaObject::<#ident>::add_object_method_set(lua, &stringify!(#field_ident), hclua::function2(|obj: &mut #ident, val: #ty| {
+                });
+                fields.add_field_set(stringify!(#field_ident), |obj: &mut #ident, val: #ty| {
                     obj.#field_ident = val;
-                }));
-                hclua::LuaObject::<#ident>::object_def(lua, &stringify!(#get_name), hclua::function1(#ident::#get_name));
-                hclua::LuaObject::<#ident>::object_def(lua, &stringify!(#set_name), hclua::function2(#ident::#set_name));
-                
-                #ident::object_static_def(lua, &stringify!(#get_name), hclua::function1(#ident::#get_name));
-                #ident::object_static_def(lua, &stringify!(#set_name), hclua::function2(#ident::#set_name));
-                
-                hclua::LuaObject::<#ident>::set_field(&stringify!(#field_ident));
+                });
+            }
+        } else {
+            quote!{}
+        }
+    }).collect();
+
+    let mut methods: Vec<_> = fields.iter().map(|field| {
+        let field_ident = field.ident.clone().unwrap();
+        if !is_skipped(field) {
+            let ty = field.ty.clone();
+            let get_name = format_ident!("get_{}", field_ident);
+            let set_name = format_ident!("set_{}", field_ident);
+            quote!{
+                methods.add_function(stringify!(#get_name), #ident::#get_name);
+                methods.add_function(stringify!(#set_name), |(obj, val): (&mut #ident, #ty)| {
+                    #ident::#set_name(obj, val)
+                });
             }
         } else {
             quote!{}
@@ -70,6 +82,11 @@ pub fn object_macro_derive(input: TokenStream) -> TokenStream {
 
     let name = config.name;
     let is_light = config.light;
+    let derive_ops: Vec<_> = config
+        .ops
+        .iter()
+        .map(|op| format_ident!("derive_{}", op))
+        .collect();
 
     functions.push({
         quote! {
@@ -79,22 +96,28 @@ pub fn object_macro_derive(input: TokenStream) -> TokenStream {
         }
     });
 
-    registers.push({
-        let is_type = format_ident!("is_type");
+    methods.push({
         quote!{
-            hclua::LuaObject::<#ident>::add_object_method_get(lua, "is_type", hclua::function2(#ident::#is_type));
+            methods.add_method_mut("is_type", |obj: &mut #ident, t: String| obj.is_type(t));
+            methods.add_function("new", |_: ()| <#ident as Default>::default());
         }
     });
 
+    methods.extend(fields.iter().filter(|field| {
+        field.attrs.iter().any(|attr| attr.path().is_ident("hclua_parent"))
+    }).map(|field| {
+        let field_ident = field.ident.clone().unwrap();
+        let ty = field.ty.clone();
+        quote!{
+            methods.inherit::<#ty>(|obj: &mut #ident| &mut obj.#field_ident);
+        }
+    }));
+
     let create_from_table: Vec<_> = fields
         .iter()
         .map(|field| {
             let field_ident = field.ident.clone().unwrap();
-            if field
-                .attrs
-                .iter()
-                .all(|attr| !attr.path().is_ident("hclua_skip"))
-            {
+            if !is_skipped(field) {
                 let ty = field.ty.clone();
                 let name = format_ident!("{}", field_ident);
                 quote! {
@@ -110,30 +133,35 @@ pub fn object_macro_derive(input: TokenStream) -> TokenStream {
         .collect();
 
     let gen = quote! {
-        impl #ident {
-            pub fn register_field(lua: &mut hclua::Lua) {
+        impl hclua::UserData for #ident {
+            const LIGHT: bool = #is_light;
+
+            fn add_fields(fields: &mut hclua::UserDataFields<Self>) {
                 #(#registers)*
             }
 
-            pub fn register(lua: &mut hclua::Lua) {
-                let mut obj = if #is_light {
-                    hclua::LuaObject::<#ident>::new_light(lua.state(), &#name)
-                } else {
-                    hclua::LuaObject::<#ident>::new(lua.state(), &#name)
-                };
-                obj.create();
+            fn add_methods(methods: &mut hclua::UserDataMethods<Self>) {
+                #(#methods)*
+                methods.add_method_mut("set_from_table", |data: &mut #ident, mut table: hclua::LuaTable| {
+                    #(#create_from_table)*
+                });
+            }
 
-                Self::register_field(lua);
+            fn add_meta_methods(methods: &mut hclua::UserDataMetaMethods<Self>) {
+                #(methods.#derive_ops();)*
+            }
+        }
 
-                Self::object_def(lua, "set_from_table", hclua::function2(|data: &mut #ident, mut table: hclua::LuaTable| {
-                    #(#create_from_table)*
-                }))
+        impl #ident {
+            pub fn register(lua: &mut hclua::Lua) {
+                lua.register_userdata::<#ident>(#name);
             }
 
             pub fn object_def<P>(lua: &mut hclua::Lua, name: &str, param: P)
             where
                 P: hclua::LuaPush,
             {
+                hclua::userdata::register_userdata::<#ident>(lua.state());
                 hclua::LuaObject::<#ident>::object_def(lua, name, param);
             }
 
@@ -141,70 +169,34 @@ pub fn object_macro_derive(input: TokenStream) -> TokenStream {
             where
                 P: hclua::LuaPush,
             {
-                let mut obj = if #is_light {
-                    hclua::LuaObject::<#ident>::new_light(lua.state(), &#name)
-                } else {
-                    hclua::LuaObject::<#ident>::new(lua.state(), &#name)
-                };
-                obj.create();
-                obj.static_def(name, param);
+                Self::register(lua);
+                if let Some(mut table) = lua.query::<hclua::LuaTable, _>(#name) {
+                    table.set(name, param);
+                }
             }
 
             pub fn object_register(lua: &mut hclua::Lua, name: &str,
                 func: extern "C" fn(*mut hclua::lua_State) -> libc::c_int)
             {
+                hclua::userdata::register_userdata::<#ident>(lua.state());
                 hclua::LuaObject::<#ident>::object_register(lua, name, func);
             }
 
             pub fn object_static_register(lua: &mut hclua::Lua, name: &str,
                 func: extern "C" fn(*mut hclua::lua_State) -> libc::c_int)
             {
-                let mut obj = if #is_light {
-                    hclua::LuaObject::<#ident>::new_light(lua.state(), &#name)
-                } else {
-                    hclua::LuaObject::<#ident>::new(lua.state(), &#name)
-                };
-                obj.create();
-                obj.static_register(name, func);
-            }
-
-
-            #(#functions)*
-        }
-
-        impl<'a> hclua::LuaRead for &'a mut #ident {
-            fn lua_read_with_pop_impl(
-                lua: *mut hclua::lua_State,
-                index: i32,
-                _pop: i32,
-            ) -> Option<&'a mut #ident> {
-                if #is_light {
-                    hclua::userdata::read_wrapper_light_userdata(lua, index)
-                } else {
-                    hclua::userdata::read_userdata(lua, index)
+                Self::register(lua);
+                if let Some(mut table) = lua.query::<hclua::LuaTable, _>(#name) {
+                    table.register(name, func);
                 }
             }
-        }
 
-        impl<'a> hclua::LuaRead for &'a #ident {
-            fn lua_read_with_pop_impl(
-                lua: *mut hclua::lua_State,
-                index: i32,
-                _pop: i32,
-            ) -> Option<&'a #ident> {
-                if #is_light {
-                    hclua::userdata::read_wrapper_light_userdata(lua, index).map(|v| &*v)
-                } else {
-                    hclua::userdata::read_userdata(lua, index).map(|v| &*v)
-                }
-            }
+            #(#functions)*
         }
 
         impl hclua::LuaPush for #ident {
             fn push_to_lua(self, lua: *mut hclua::lua_State) -> i32 {
-                unsafe {
-                    hclua::userdata::push_wrapper_lightuserdata(self, lua, |_| {})
-                }
+                hclua::push_object(self, lua)
             }
             fn box_push_to_lua(self: Box<Self>, lua: *mut hclua::lua_State) -> i32
             {
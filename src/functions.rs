@@ -1,3 +1,5 @@
+use crate::object::ArgBorrows;
+use crate::protocol::raise_on_error;
 use crate::{lua_State, sys, LuaPush, LuaRead};
 use libc;
 
@@ -86,26 +88,28 @@ macro_rules! impl_wrapper {
                 }
             }
 
+            // objects read as arguments stay borrowed until the function returns
+            let borrows = ArgBorrows::open(lua);
             let args = match LuaRead::lua_read_at_position(lua, -arguments_count as libc::c_int) {
                 Some(a) => a,
                 _ => {
+                    drop(borrows);
                     let err_msg = format!(
                         "wrong parameter types for callback function arguments_count \
                                         is {} all have num {} may type not match",
                         arguments_count, $num
                     );
-                    err_msg.push_to_lua(lua);
-                    unsafe {
-                        sys::lua_error(lua);
-                    }
+                    return raise_on_error(lua, Err(err_msg));
                 }
             };
 
             let ret_value = data.call_mut(args);
 
-            // pushing back the result of the function on the stack
-            let nb = ret_value.push_to_lua(lua);
-            nb as libc::c_int
+            // pushing back the result of the function on the stack, an error is raised once
+            // the arguments are given back
+            let ret = ret_value.try_push_to_lua(lua);
+            drop(borrows);
+            raise_on_error(lua, ret)
         }
     )
 }
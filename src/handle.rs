@@ -0,0 +1,301 @@
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::os::raw::c_void;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::object::LightObject;
+use crate::userdata::{light_object, push_light_object, push_object, register_userdata};
+use crate::{impl_box_push, lua_State, sys, Lua, LuaPush, LuaRead, UserData};
+
+/// Who releases the value of an object.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ownership {
+    /// Lua owns the value, it is dropped by the GC or by `del`
+    Owned,
+    /// the value is shared with Rust, Lua only holds a reference count and mutates it through
+    /// its lock (`Rc<RefCell<T>>`, `Arc<Mutex<T>>`, `Arc<RwLock<T>>`) if it has one
+    Shared,
+    /// Rust owns the value, the object is released when the borrow ends
+    Borrowed,
+}
+
+/// A `UserData` value moved into Lua, dropped by the GC (or early by `del` on `LIGHT` classes).
+///
+/// Reading an `Owned<T>` moves the value back out of Lua, the object then reads as released
+/// and using it from a script raises an error.
+pub struct Owned<T>(pub T);
+
+impl<T: UserData> LuaPush for Owned<T> {
+    fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+        push_object(self.0, lua)
+    }
+    impl_box_push!();
+}
+
+impl<T: UserData> LuaRead for Owned<T> {
+    fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop: i32) -> Option<Self> {
+        light_object::<T>(lua, index)?.take().map(Owned)
+    }
+}
+
+/// A `UserData` value kept alive by both Rust and Lua.
+///
+/// The object holds its own count on the `Rc` / `Arc`, released by the GC or `del`, so
+/// Rust dropping its side changes nothing for scripts. Scripts only get `&T`, mutating
+/// methods and setters raise an error.
+pub enum Shared<T> {
+    Rc(Rc<T>),
+    Arc(Arc<T>),
+}
+
+impl<T> From<Rc<T>> for Shared<T> {
+    fn from(value: Rc<T>) -> Self {
+        Shared::Rc(value)
+    }
+}
+
+impl<T> From<Arc<T>> for Shared<T> {
+    fn from(value: Arc<T>) -> Self {
+        Shared::Arc(value)
+    }
+}
+
+unsafe fn release_rc<H>(ptr: *mut c_void, _: bool) {
+    drop(Rc::from_raw(ptr as *const H));
+}
+
+unsafe fn release_arc<H>(ptr: *mut c_void, _: bool) {
+    drop(Arc::from_raw(ptr as *const H));
+}
+
+impl<T: UserData> LuaPush for Shared<T> {
+    fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+        register_userdata::<T>(lua);
+        let object = match self {
+            Shared::Rc(v) => LightObject::from_raw::<T, Rc<T>>(
+                Rc::into_raw(v) as *mut c_void,
+                Ownership::Shared,
+                release_rc::<T>,
+            ),
+            Shared::Arc(v) => LightObject::from_raw::<T, Arc<T>>(
+                Arc::into_raw(v) as *mut c_void,
+                Ownership::Shared,
+                release_arc::<T>,
+            ),
+        };
+        push_light_object::<T>(object, lua)
+    }
+    impl_box_push!();
+}
+
+/// another count on the `Rc` / `Arc` held by a shared object
+impl<T: UserData> LuaRead for Shared<T> {
+    fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop: i32) -> Option<Self> {
+        if let Some(ptr) = holder_ptr::<T, Rc<T>>(lua, index) {
+            return Some(Shared::Rc(unsafe { clone_rc(ptr as *const T) }));
+        }
+        let ptr = holder_ptr::<T, Arc<T>>(lua, index)?;
+        Some(Shared::Arc(unsafe { clone_arc(ptr as *const T) }))
+    }
+}
+
+/// the pointer of the `T` object at `index` when it was made from an `H`
+fn holder_ptr<T: UserData, H: Any>(lua: *mut lua_State, index: i32) -> Option<*mut c_void> {
+    let obj = light_object::<T>(lua, index)?;
+    if obj.holder != TypeId::of::<H>() || obj.ptr.is_null() {
+        return None;
+    }
+    Some(obj.ptr)
+}
+
+unsafe fn clone_rc<H>(ptr: *const H) -> Rc<H> {
+    Rc::increment_strong_count(ptr);
+    Rc::from_raw(ptr)
+}
+
+unsafe fn clone_arc<H>(ptr: *const H) -> Arc<H> {
+    Arc::increment_strong_count(ptr);
+    Arc::from_raw(ptr)
+}
+
+unsafe fn lock_refcell<T>(
+    ptr: *mut c_void,
+    mutable: bool,
+    f: &mut dyn FnMut(*mut c_void),
+) -> Result<(), &'static str> {
+    let cell = &*(ptr as *const RefCell<T>);
+    if mutable {
+        let mut value = cell.try_borrow_mut().map_err(|_| "is already borrowed")?;
+        f(&mut *value as *mut T as *mut c_void);
+        Ok(())
+    } else {
+        let value = cell.try_borrow().map_err(|_| "is already mutably borrowed")?;
+        f(&*value as *const T as *mut c_void);
+        Ok(())
+    }
+}
+
+unsafe fn lock_mutex<T>(
+    ptr: *mut c_void,
+    _mutable: bool,
+    f: &mut dyn FnMut(*mut c_void),
+) -> Result<(), &'static str> {
+    let mutex = &*(ptr as *const Mutex<T>);
+    let mut value = mutex.lock().map_err(|_| "is poisoned")?;
+    f(&mut *value as *mut T as *mut c_void);
+    Ok(())
+}
+
+unsafe fn lock_rwlock<T>(
+    ptr: *mut c_void,
+    mutable: bool,
+    f: &mut dyn FnMut(*mut c_void),
+) -> Result<(), &'static str> {
+    let lock = &*(ptr as *const RwLock<T>);
+    if mutable {
+        let mut value = lock.write().map_err(|_| "is poisoned")?;
+        f(&mut *value as *mut T as *mut c_void);
+        Ok(())
+    } else {
+        let value = lock.read().map_err(|_| "is poisoned")?;
+        f(&*value as *const T as *mut c_void);
+        Ok(())
+    }
+}
+
+/// Shared objects with interior mutability, scripts use the fields and methods of `T`.
+///
+/// Every access borrows the value for the call, a borrow conflict is raised as a Lua error.
+/// `Mutex` and `RwLock` block while another thread holds them.
+macro_rules! impl_locked_push {
+    ($holder:ident, $lock:ident, $into_raw:path, $release:ident, $clone:ident, $lock_fn:ident) => {
+        impl<T: UserData> LuaPush for $holder<$lock<T>> {
+            fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+                register_userdata::<T>(lua);
+                let object = LightObject::locked::<T, $holder<$lock<T>>>(
+                    $into_raw(self) as *mut c_void,
+                    $release::<$lock<T>>,
+                    $lock_fn::<T>,
+                );
+                push_light_object::<T>(object, lua)
+            }
+            impl_box_push!();
+        }
+
+        /// another count on the shared value of the object
+        impl<T: UserData> LuaRead for $holder<$lock<T>> {
+            fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop: i32) -> Option<Self> {
+                let ptr = holder_ptr::<T, $holder<$lock<T>>>(lua, index)?;
+                Some(unsafe { $clone(ptr as *const $lock<T>) })
+            }
+        }
+    };
+}
+
+impl_locked_push!(Rc, RefCell, Rc::into_raw, release_rc, clone_rc, lock_refcell);
+impl_locked_push!(Arc, Mutex, Arc::into_raw, release_arc, clone_arc, lock_mutex);
+impl_locked_push!(Arc, RwLock, Arc::into_raw, release_arc, clone_arc, lock_rwlock);
+
+unsafe fn release_borrowed(_: *mut c_void, _: bool) {}
+
+/// A `UserData` value lent to Lua for as long as the handle lives, made by `Scope::lend_mut`.
+///
+/// The object is kept in the registry and pushed with `&handle`. Dropping the handle
+/// releases it, scripts still holding it then get an error instead of a dangling value.
+/// Only the scope creates handles: one that was forgotten would leave the object pointing at
+/// a value Rust already dropped.
+pub struct Borrowed<'a, T: UserData> {
+    lua: *mut lua_State,
+    reference: i32,
+    marker: PhantomData<&'a mut T>,
+}
+
+impl<'a, T: UserData> Borrowed<'a, T> {
+    pub(crate) fn new(lua: *mut lua_State, value: &'a mut T) -> Self {
+        register_userdata::<T>(lua);
+        let object = LightObject::from_raw::<T, *mut T>(
+            value as *mut T as *mut c_void,
+            Ownership::Borrowed,
+            release_borrowed,
+        );
+        push_light_object::<T>(object, lua);
+        let reference = unsafe { sys::luaL_ref(lua, sys::LUA_REGISTRYINDEX) };
+        Borrowed {
+            lua,
+            reference,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, 'b, T: UserData> LuaPush for &'b Borrowed<'a, T> {
+    fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+        unsafe { sys::lua_rawgeti(lua, sys::LUA_REGISTRYINDEX, self.reference as _) };
+        1
+    }
+    impl_box_push!();
+}
+
+impl<'a, T: UserData> Drop for Borrowed<'a, T> {
+    fn drop(&mut self) {
+        unsafe {
+            sys::lua_rawgeti(self.lua, sys::LUA_REGISTRYINDEX, self.reference as _);
+            if let Some(obj) = light_object::<T>(self.lua, -1) {
+                obj.revoke();
+            }
+            sys::lua_pop(self.lua, 1);
+            sys::luaL_unref(self.lua, sys::LUA_REGISTRYINDEX, self.reference);
+        }
+    }
+}
+
+/// objects lent by a `Scope`, released when it ends
+trait Lent {}
+
+impl<'a, T: UserData> Lent for Borrowed<'a, T> {}
+
+/// Lends values to Lua for the duration of `Lua::scope`.
+///
+/// The scope derefs to the `Lua` it was opened on, so scripts are run through it while the
+/// values are lent. Every lent object is released when the scope ends, a script that kept
+/// one raises an error when using it.
+pub struct Scope<'a> {
+    lua: Lua,
+    lent: Vec<Box<dyn Lent + 'a>>,
+    marker: PhantomData<&'a mut Lua>,
+}
+
+impl<'a> Scope<'a> {
+    pub(crate) fn new(lua: &'a mut Lua) -> Self {
+        Scope {
+            lua: lua.clone(),
+            lent: Vec::new(),
+            marker: PhantomData,
+        }
+    }
+
+    /// sets the global `name` to `value` until the scope ends
+    pub fn lend_mut<T: UserData>(&mut self, name: &str, value: &'a mut T) -> &mut Self {
+        let borrowed = Borrowed::new(self.lua.state(), value);
+        self.lua.set(name, &borrowed);
+        self.lent.push(Box::new(borrowed));
+        self
+    }
+}
+
+impl<'a> Deref for Scope<'a> {
+    type Target = Lua;
+
+    fn deref(&self) -> &Lua {
+        &self.lua
+    }
+}
+
+impl<'a> DerefMut for Scope<'a> {
+    fn deref_mut(&mut self) -> &mut Lua {
+        &mut self.lua
+    }
+}
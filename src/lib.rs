@@ -19,7 +19,7 @@ pub use hclua_macro::{lua_module, ObjectMacro};
 pub use protocol::*;
 
 use lazy_static::lazy_static;
-use std::borrow::Borrow;
+use std::borrow::{Borrow, Cow};
 use std::collections::HashMap;
 use std::ffi::{c_void, CStr, CString};
 use std::fs::File;
@@ -27,6 +27,7 @@ use std::io::prelude::*;
 use std::sync::RwLock;
 
 pub mod functions;
+pub mod handle;
 mod hotfix;
 pub mod lua_tables;
 mod mem;
@@ -41,11 +42,16 @@ pub use functions::{
     function0, function1, function10, function2, function3, function4, function5, function6,
     function7, function8, function9, Function,
 };
+pub use handle::{Borrowed, Owned, Ownership, Scope, Shared};
 pub use lua_tables::LuaTable;
 pub use module::LuaModule;
 pub use object::{LuaObject, WrapObject};
-pub use userdata::{push_lightuserdata, push_userdata, read_userdata, read_wrapper_light_userdata, read_pop_wrapper_light_userdata};
-pub use values::{RawString, WrapperObject};
+pub use userdata::{
+    push_lightuserdata, push_object, push_userdata, read_object, read_object_ref,
+    read_pop_wrapper_light_userdata, read_userdata, read_wrapper_light_userdata, MetaMethod,
+    UserData, UserDataFields, UserDataMetaMethods, UserDataMethods,
+};
+pub use values::{LuaStr, RawString, Strict, WrapperObject};
 
 struct WrapCvoid(*mut libc::c_void);
 unsafe impl Send for WrapCvoid {}
@@ -68,6 +74,26 @@ pub struct LuaGuard {
 }
 
 impl LuaGuard {
+    /// Reads the string at `index` without copying it.
+    ///
+    /// The returned view borrows the guard, so it can't outlive the values the guard keeps
+    /// on the stack.
+    pub fn read_str(&self, index: i32) -> Option<LuaStr<'_>> {
+        values::read_lstring(self.lua, index).map(LuaStr)
+    }
+
+    /// Reads the bytes of the string at `index` without copying, borrowing the guard like
+    /// `read_str`.
+    pub fn read_bytes(&self, index: i32) -> Option<&[u8]> {
+        values::read_lstring(self.lua, index)
+    }
+
+    /// Reads the string at `index` as text, borrowed when it is valid UTF-8 and copied with
+    /// the invalid sequences replaced otherwise.
+    pub fn read_cow(&self, index: i32) -> Option<Cow<'_, str>> {
+        values::read_lstring(self.lua, index).map(String::from_utf8_lossy)
+    }
+
     pub fn forget(mut self) -> i32 {
         let size = self.size;
         self.size = 0;
@@ -104,6 +130,22 @@ macro_rules! impl_box_push {
 }
 
 
+/// implements `LuaPush` for a `UserData` type, values are pushed with `push_object`
+#[macro_export]
+macro_rules! impl_userdata_push {
+    ($ty: ty) => {
+        impl $crate::LuaPush for $ty {
+            fn push_to_lua(self, lua: *mut $crate::lua_State) -> i32 {
+                $crate::push_object(self, lua)
+            }
+
+            fn box_push_to_lua(self: Box<Self>, lua: *mut $crate::lua_State) -> i32 {
+                (*self).push_to_lua(lua)
+            }
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! impl_obj_fn {
     ($ty: ty, $lua: ident, $field: ident, $name: ident) => {
@@ -269,6 +311,54 @@ impl Lua {
         0
     }
 
+    /// Calls `func` as the loader of the module `name`, stores the result in `package.loaded`
+    /// and, when `global` is set, in the global `name`. `require(name)` then returns it.
+    pub fn requiref<I>(
+        &mut self,
+        name: I,
+        func: extern "C" fn(*mut lua_State) -> libc::c_int,
+        global: bool,
+    ) where
+        I: Borrow<str>,
+    {
+        let name = CString::new(name.borrow()).unwrap();
+        unsafe {
+            luaL_requiref(self.state(), name.as_ptr(), func, global as libc::c_int);
+            lua_pop(self.state(), 1);
+        }
+    }
+
+    /// Registers the `UserData` type `T` and sets its class table, holding the functions added
+    /// with `add_function`, as the global `name`.
+    pub fn register_userdata<T: UserData>(&mut self, name: &str) {
+        userdata::register_userdata::<T>(self.lua);
+        let name = CString::new(name).unwrap();
+        unsafe {
+            // the name `is_a` compares with
+            userdata::get_type_metatable::<T>(self.lua);
+            lua_pushstring(self.lua, name.as_ptr());
+            lua_setfield(self.lua, -2, cstr!("__class"));
+            lua_pop(self.lua, 1);
+        }
+        userdata::get_object_table::<T>(self.lua, cstr!("__statics"));
+        unsafe { lua_setglobal(self.lua, name.as_ptr()) };
+    }
+
+    /// Runs `f` with a `Scope` lending Rust values to scripts, they are released when it returns:
+    ///
+    /// ```ignore
+    /// lua.scope(|s| {
+    ///     s.lend_mut("world", &mut world);
+    ///     s.exec_string::<_, ()>("world:step()")
+    /// });
+    /// ```
+    pub fn scope<'a, R, F>(&'a mut self, f: F) -> R
+    where
+        F: FnOnce(&mut Scope<'a>) -> R,
+    {
+        f(&mut Scope::new(self))
+    }
+
     /// Opens all standard Lua libraries.
     /// This is done by calling `luaL_openlibs`.
     pub fn openlibs(&mut self) {
@@ -288,6 +378,20 @@ impl Lua {
         LuaRead::lua_read_with_pop(self.state(), -1, 1)
     }
 
+    /// Pushes the value of a global variable and returns the guard that pops it.
+    ///
+    /// Used to read borrowed values such as `LuaStr` from the top of the stack.
+    pub fn query_guard<I>(&mut self, index: I) -> LuaGuard
+    where
+        I: Borrow<str>,
+    {
+        let index = CString::new(index.borrow()).unwrap();
+        unsafe {
+            lua_getglobal(self.lua, index.as_ptr());
+        }
+        LuaGuard::new(self.lua, 1)
+    }
+
     /// Reads the value of a global variable.
     pub fn queryc<'l, V>(&'l mut self, index: &CString) -> Option<V>
     where
@@ -628,6 +732,12 @@ impl Lua {
         hotfix::load_hot_fix(self);
     }
 
+    /// sets the global `hclua` table: `hclua.array(t)` marks a table as an array for the
+    /// serde and protocol conversions, `hclua.null` is the null sentinel
+    pub fn enable_serde(&mut self) {
+        protocol::load_serde_helpers(self.state());
+    }
+
     pub fn exec_gc(&mut self) -> i32 {
         unsafe { lua_gc(self.state(), LUA_GCCOLLECT, 0) as i32 }
     }
@@ -669,6 +779,41 @@ pub trait LuaPush {
     /// `userdata::push_userdata`.
     fn push_to_lua(self, lua: *mut lua_State) -> i32;
 
+    /// Pushes the value like `push_to_lua`, a value that can't be pushed is returned as an `Err`
+    /// instead of being raised, for callers that still have something to release first.
+    fn try_push_to_lua(self, lua: *mut lua_State) -> Result<i32, String>
+    where
+        Self: Sized,
+    {
+        Ok(self.push_to_lua(lua))
+    }
+
+    /// Pushes the value as one element of a table, returns 1 if a value is left on the stack,
+    /// 0 if nothing was pushed.
+    ///
+    /// A value pushing several values is packed into an array of its own, tuples pack each of
+    /// their elements the same way so `(1, ("a", true))` becomes `{1, {"a", true}}`.
+    fn push_packed_to_lua(self, lua: *mut lua_State) -> i32
+    where
+        Self: Sized,
+    {
+        unsafe {
+            let top = lua_gettop(lua);
+            let size = self.push_to_lua(lua);
+            if size <= 1 {
+                return size;
+            }
+            lua_createtable(lua, size, 0);
+            for i in 1..(size + 1) {
+                lua_pushvalue(lua, top + i);
+                lua_rawseti(lua, -2, i as lua_Integer);
+            }
+            lua_insert(lua, top + 1);
+            lua_settop(lua, top + 1);
+        }
+        1
+    }
+
     // fn box_push_to_lua(self: Box<Self>, lua: *mut lua_State) -> i32;
     fn box_push_to_lua(self: Box<Self>, lua: *mut lua_State) -> i32 {
         let _lua = lua;
@@ -698,6 +843,19 @@ pub trait LuaRead: Sized {
     }
 
     fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, pop: i32) -> Option<Self>;
+
+    /// Number of consecutive stack slots the value is read from.
+    ///
+    /// Tuples span one slot per element (nested tuples are flattened), everything else uses one.
+    fn lua_read_slots() -> i32 {
+        1
+    }
+
+    /// Reads one element of a table, the inverse of `LuaPush::push_packed_to_lua`: a tuple is
+    /// read from the array at `index`, each of its elements read packed in turn.
+    fn lua_read_packed(lua: *mut lua_State, index: i32) -> Option<Self> {
+        LuaRead::lua_read_at_position(lua, index)
+    }
 }
 
 impl Drop for Lua {
@@ -1,7 +1,5 @@
-use lazy_static::lazy_static;
 use libc::c_char;
 use std::any::type_name;
-use std::collections::HashSet;
 use std::ffi::CStr;
 use std::mem::size_of;
 use std::os::raw::c_void;
@@ -10,31 +8,239 @@ use std::{
     ffi::CString,
     marker::PhantomData,
     mem, ptr,
-    sync::RwLock,
 };
 
 use crate::{
-    luaL_error, lua_State, lua_call, lua_error, lua_getfield, lua_gettop, lua_pushvalue,
-    push_lightuserdata, sys, Lua, LuaPush, LuaRead, LuaTable, WrapCvoid,
+    lua_State, lua_error, push_lightuserdata, sys, Lua, LuaPush, LuaRead, LuaTable, WrapCvoid,
 };
-
-lazy_static! {
-    static ref FIELD_CHECK: RwLock<HashSet<(&'static str, &'static str)>> = RwLock::new(HashSet::new());
-}
-
+use crate::handle::Ownership;
+use crate::userdata::{
+    delete_light_object, ensure_object_metatable, get_object_table, get_type_metatable, is_field,
+    is_reserved_meta, mark_field, push_inline_object, with_type_registry,
+};
+use log::warn;
+
+/// Runs a call with the value behind a lock (`RefCell`, `Mutex`, `RwLock`), mutably or not.
+/// `Err` tells why the value can't be borrowed now.
+///
+/// The call must not raise a Lua error, the jump would skip releasing the lock.
+pub(crate) type Lock =
+    unsafe fn(*mut c_void, bool, &mut dyn FnMut(*mut c_void)) -> Result<(), &'static str>;
+
+/// The head of every object userdata: where the value lives and who releases it.
+///
+/// `ptr` is null once the value was released, the object then only raises errors.
 pub struct LightObject {
     pub ptr: *mut c_void,
-    pub name: &'static str,
+    pub type_id: TypeId,
+    /// the type `ptr` was made from, such as `Box<T>` or `Rc<RefCell<T>>`
+    pub holder: TypeId,
+    pub ownership: Ownership,
+    /// frees what `ptr` holds, `false` when the value was moved out first
+    release: unsafe fn(*mut c_void, bool),
+    /// set when `ptr` is the lock holding the value rather than the value
+    lock: Option<Lock>,
+    /// borrows of the value by the calls running in this state, -1 when mutable
+    borrows: isize,
 }
 
 impl LightObject {
-    pub fn new<T>(val: T) -> Self {
+    /// an owned value boxed by Rust
+    pub fn new<T: Any>(val: T) -> Self {
+        Self::from_raw::<T, Box<T>>(
+            Box::into_raw(Box::new(val)) as *mut c_void,
+            Ownership::Owned,
+            release_box::<T>,
+        )
+    }
+
+    pub(crate) fn from_raw<T: Any, H: Any>(
+        ptr: *mut c_void,
+        ownership: Ownership,
+        release: unsafe fn(*mut c_void, bool),
+    ) -> Self {
+        LightObject {
+            ptr,
+            type_id: TypeId::of::<T>(),
+            holder: TypeId::of::<H>(),
+            ownership,
+            release,
+            lock: None,
+            borrows: 0,
+        }
+    }
+
+    /// a value shared behind a lock, reached through `lock` for each call
+    pub(crate) fn locked<T: Any, H: Any>(
+        ptr: *mut c_void,
+        release: unsafe fn(*mut c_void, bool),
+        lock: Lock,
+    ) -> Self {
+        LightObject {
+            lock: Some(lock),
+            ..Self::from_raw::<T, H>(ptr, Ownership::Shared, release)
+        }
+    }
+
+    /// whether the value can only be reached through its lock
+    pub fn is_locked(&self) -> bool {
+        self.lock.is_some()
+    }
+
+    /// releases the value once, a second call does nothing
+    ///
+    /// A value borrowed by a running call is left to the GC.
+    pub fn release(&mut self) {
+        if self.borrows != 0 {
+            return;
+        }
+        let ptr = mem::replace(&mut self.ptr, ptr::null_mut());
+        if !ptr.is_null() {
+            unsafe { (self.release)(ptr, true) };
+        }
+    }
+
+    /// Forgets a `Borrowed` value whose lender is gone, even while a call still borrows it: the
+    /// value can't outlive its scope. Nothing is dropped, the lender owns it.
+    pub(crate) fn revoke(&mut self) {
+        debug_assert!(self.ownership == Ownership::Borrowed);
+        self.ptr = ptr::null_mut();
+    }
+
+    /// moves an owned value out, the object reads as released afterwards
+    pub fn take<T: Any>(&mut self) -> Option<T> {
+        if self.ownership != Ownership::Owned
+            || self.type_id != TypeId::of::<T>()
+            || self.ptr.is_null()
+            || self.borrows != 0
+        {
+            return None;
+        }
+        let ptr = mem::replace(&mut self.ptr, ptr::null_mut());
         unsafe {
-            LightObject {
-                ptr: Box::into_raw(Box::new(val)) as *mut c_void,
-                name: type_name::<T>(),
+            let val = ptr::read(ptr as *mut T);
+            (self.release)(ptr, false);
+            Some(val)
+        }
+    }
+}
+
+/// Runs `f` with the value of `obj`, borrowed through its lock when it has one.
+///
+/// Borrows by this state are counted on the object, so a reentrant call or an argument already
+/// borrowing the value conflicts instead of aliasing it or deadlocking on a `Mutex`. Like the
+/// lock, `f` must not raise a Lua error.
+pub(crate) unsafe fn with_value(
+    obj: *mut LightObject,
+    mutable: bool,
+    f: &mut dyn FnMut(*mut c_void),
+) -> Result<(), &'static str> {
+    let _borrow = BorrowGuard::new(obj, mutable)?;
+    match (*obj).lock {
+        Some(lock) => lock((*obj).ptr, mutable, f),
+        None => {
+            f((*obj).ptr);
+            Ok(())
+        }
+    }
+}
+
+/// The borrows made by the arguments of the calls from Lua running in one state, kept in its
+/// `TypeRegistry`.
+#[derive(Default)]
+pub(crate) struct ArgFrames {
+    borrows: Vec<BorrowGuard>,
+    /// how many of these calls are running
+    open: usize,
+}
+
+impl ArgFrames {
+    /// Forgets the borrows left when the state is closed, their objects may already be gone.
+    pub(crate) fn forget(&mut self) {
+        mem::forget(mem::take(&mut self.borrows));
+        self.open = 0;
+    }
+}
+
+/// The borrows made by the `&T` and `&mut T` arguments of one call from Lua, given back when the
+/// call ends. Opened by the wrappers of Rust functions before reading their arguments.
+///
+/// The wrappers drop it before raising any error, a `lua_error` jumping over it would leave the
+/// objects borrowed.
+pub(crate) struct ArgBorrows {
+    lua: *mut lua_State,
+    start: usize,
+}
+
+impl ArgBorrows {
+    pub(crate) fn open(lua: *mut lua_State) -> Self {
+        let start = with_type_registry(lua, |registry| {
+            registry.arguments.open += 1;
+            registry.arguments.borrows.len()
+        });
+        ArgBorrows { lua, start }
+    }
+}
+
+impl Drop for ArgBorrows {
+    fn drop(&mut self) {
+        let ended = with_type_registry(self.lua, |registry| {
+            registry.arguments.open -= 1;
+            registry.arguments.borrows.split_off(self.start)
+        });
+        drop(ended);
+    }
+}
+
+/// Counts a borrow of `obj` by an argument of the running call, false when it conflicts with a
+/// borrow already made, such as `obj:f(obj)` asking for `&mut T` twice.
+///
+/// Values read outside of a call from Lua aren't counted.
+pub(crate) fn borrow_argument(lua: *mut lua_State, obj: &mut LightObject, mutable: bool) -> bool {
+    with_type_registry(lua, |registry| {
+        if registry.arguments.open == 0 {
+            return true;
+        }
+        match unsafe { BorrowGuard::new(obj, mutable) } {
+            Ok(guard) => {
+                registry.arguments.borrows.push(guard);
+                true
             }
+            Err(_) => false,
         }
+    })
+}
+
+/// one borrow counted on an object, given back when dropped
+struct BorrowGuard {
+    obj: *mut LightObject,
+    step: isize,
+}
+
+impl BorrowGuard {
+    unsafe fn new(obj: *mut LightObject, mutable: bool) -> Result<Self, &'static str> {
+        match (*obj).borrows {
+            b if b < 0 => return Err("is already mutably borrowed"),
+            b if b > 0 && mutable => return Err("is already borrowed"),
+            _ => (),
+        }
+        let step = if mutable { -1 } else { 1 };
+        (*obj).borrows += step;
+        Ok(BorrowGuard { obj, step })
+    }
+}
+
+impl Drop for BorrowGuard {
+    fn drop(&mut self) {
+        unsafe { (*self.obj).borrows -= self.step };
+    }
+}
+
+unsafe fn release_box<T>(ptr: *mut c_void, drop_value: bool) {
+    if drop_value {
+        drop(Box::from_raw(ptr as *mut T));
+    } else {
+        drop(Box::from_raw(ptr as *mut mem::MaybeUninit<T>));
     }
 }
 
@@ -54,99 +260,32 @@ where
     T: Default + Any,
     &'a mut T: LuaRead,
 {
-    pub fn is_field(name: &str) -> bool {
-        let val = FIELD_CHECK.read().unwrap();
-        val.contains(&(type_name::<T>(), name))
-    }
-
-    pub fn set_field(name: &'static str) {
-        let mut val = FIELD_CHECK.write().unwrap();
-        val.insert((type_name::<T>(), name));
+    /// whether `name` is a field of `T` in this state
+    pub fn is_field_in(lua: &mut Lua, name: &str) -> bool {
+        is_field::<T>(lua.state(), name)
     }
 
-    fn get_metatable_base_key() -> CString {
-        CString::new(type_name::<T>()).unwrap()
+    /// marks `name` as a field of `T` in this state, read by its getter and written by its setter
+    pub fn set_field_in(lua: &mut Lua, name: &str) {
+        mark_field::<T>(lua.state(), name)
     }
 
-    fn get_metatable_real_key() -> CString {
-        CString::new(format!("{:?}_real", type_name::<T>())).unwrap()
+    /// Reads the table holding the methods of `T`, it lives in the metatable under
+    /// `__methods` and is the upvalue of `__index`.
+    fn methods_table(lua: &mut Lua) -> Option<LuaTable> {
+        get_object_table::<T>(lua.state(), cstr!("__methods"));
+        LuaRead::lua_read_with_pop(lua.state(), -1, 1)
     }
 
-    fn get_set_field_key(name: &str) -> CString {
-        CString::new(format!("{}__set", name)).unwrap()
-    }
-
-    /// 元表的index操作, 处理字段及函数的映射
-    extern "C" fn index_metatable(lua: *mut sys::lua_State) -> libc::c_int {
-        unsafe {
-            if lua_gettop(lua) < 2 {
-                let value = CString::new(format!("index field must use 2 top")).unwrap();
-                return luaL_error(lua, value.as_ptr());
-            }
-        }
-        if let Some(key) = String::lua_read_with_pop(lua, 2, 0) {
-            let typeid = Self::get_metatable_real_key();
-            unsafe {
-                sys::lua_getglobal(lua, typeid.as_ptr());
-                let is_field = LuaObject::is_field(&*key);
-                let key = CString::new(key).unwrap();
-                let t = lua_getfield(lua, -1, key.as_ptr());
-                if !is_field {
-                    if t == sys::LUA_TFUNCTION {
-                        return 1;
-                    } else {
-                        return 1;
-                    }
-                }
-                lua_pushvalue(lua, 1);
-                lua_call(lua, 1, 1);
-                1
-            }
-        } else {
-            0
-        }
-    }
-
-    extern "C" fn newindex_metatable(lua: *mut sys::lua_State) -> libc::c_int {
-        if let Some(mut key) = String::lua_read_with_pop(lua, 2, 0) {
-            if !LuaObject::is_field(&*key) {
-                let value = CString::new(format!("key {key} not a field")).unwrap();
-                unsafe {
-                    return luaL_error(lua, value.as_ptr());
-                }
-            }
-            key.push_str("__set");
-            let typeid = Self::get_metatable_real_key();
-            unsafe {
-                sys::lua_getglobal(lua, typeid.as_ptr());
-                let key = CString::new(key).unwrap();
-                let t = lua_getfield(lua, -1, key.as_ptr());
-                if t != sys::LUA_TFUNCTION {
-                    return 0;
-                }
-                lua_pushvalue(lua, 1);
-                lua_pushvalue(lua, 3);
-                lua_call(lua, 2, 1);
-                1
-            }
-        } else {
-            0
-        }
+    /// the table of the field setters, the upvalue of `__newindex`
+    fn setters_table(lua: &mut Lua) -> Option<LuaTable> {
+        get_object_table::<T>(lua.state(), cstr!("__setters"));
+        LuaRead::lua_read_with_pop(lua.state(), -1, 1)
     }
 
+    // the value lives in the userdata behind its `LightObject`, like every object of `T`
     extern "C" fn constructor_wrapper(lua: *mut sys::lua_State) -> libc::c_int {
-        let t = T::default();
-        let lua_data_raw =
-            unsafe { sys::lua_newuserdata(lua, mem::size_of::<T>() as libc::size_t) };
-        unsafe {
-            ptr::write(lua_data_raw as *mut _, t);
-        }
-        let typeid = CString::new(type_name::<T>()).unwrap();
-        unsafe {
-            sys::lua_getglobal(lua, typeid.as_ptr());
-            sys::lua_setmetatable(lua, -2);
-        }
-        1
+        push_inline_object(T::default(), lua)
     }
 
     // constructor direct create light object,
@@ -157,66 +296,6 @@ where
         crate::userdata::push_wrapper_lightuserdata(T::default(), lua, |_| {})
     }
 
-    #[inline]
-    extern "C" fn delete_light_wrapper(lua: *mut sys::lua_State) -> libc::c_int
-    where
-        &'a mut T: LuaRead,
-    {
-        unsafe {
-            if sys::lua_isuserdata(lua, 1) == 0 {
-                return 0;
-            }
-            let data_ptr = sys::lua_touserdata(lua, 1);
-            if data_ptr.is_null() {
-                return 0;
-            }
-            let expected_typeid = type_name::<T>();
-            let obj: &mut LightObject = std::mem::transmute(data_ptr);
-            if obj.name != expected_typeid || obj.ptr.is_null() {
-                return 0;
-            }
-            let val: &mut T = mem::transmute(obj.ptr);
-            let _val = Box::from_raw(val);
-            obj.ptr = ptr::null_mut() as *mut c_void;
-        }
-        0
-    }
-
-    
-    #[inline]
-    extern "C" fn destructor_light_gc(lua: *mut sys::lua_State) -> libc::c_int
-    where
-        &'a mut T: LuaRead,
-    {
-        println!("destructor_light_wrapper aaaaa {:?}", type_name::<T>());
-        // let msg: &mut T = unwrap_or!(crate::LuaRead::lua_read_at_position(lua, 1), return 0);
-        // println!("destructor_light_wrapper bbbbbbbbbb");
-        // let _msg = unsafe { Box::from_raw(msg) };
-        unsafe {
-            if sys::lua_isuserdata(lua, 1) == 0 {
-                return 0;
-            }
-            let data_ptr = sys::lua_touserdata(lua, 1);
-            if data_ptr.is_null() {
-                return 0;
-            }
-            
-            loop {
-                let expected_typeid = type_name::<T>();
-                let obj: &mut LightObject = std::mem::transmute(data_ptr);
-                if obj.name != expected_typeid || obj.ptr.is_null() {
-                    break;
-                }
-                let val: &mut T = mem::transmute(obj.ptr);
-                let _val = Box::from_raw(val);
-                break;
-            }
-            ptr::drop_in_place(data_ptr as *mut LightObject);
-        }
-        println!("destructor_light_wrapper bbbbbbbbbb");
-        0
-    }
-
     #[inline]
     extern "C" fn destructor_bad_wrapper(lua: *mut sys::lua_State) -> libc::c_int {
         unsafe {
@@ -226,16 +305,6 @@ where
         0
     }
 
-    #[inline]
-    extern "C" fn destructor_wrapper(lua: *mut sys::lua_State) -> libc::c_int {
-        unsafe {
-            let obj = sys::lua_touserdata(lua, -1);
-            ptr::drop_in_place(obj as *mut T);
-            0
-        }
-    }
-
-    
     pub fn new(lua: *mut lua_State, name: &'static str) -> LuaObject<'a, T> {
         LuaObject {
             lua,
@@ -255,47 +324,9 @@ where
     }
 
     pub fn ensure_matetable(&mut self) -> bool {
-        let typeid = Self::get_metatable_base_key();
-        let mut lua = Lua::from_existing_state(self.lua, false);
-        match lua.queryc::<LuaTable>(&typeid) {
-            Some(_) => true,
-            None => unsafe {
-                sys::lua_newtable(self.lua);
-                // index "__name" corresponds to the hash of the TypeId of T
-                "__typeid".push_to_lua(self.lua);
-                (&typeid).push_to_lua(self.lua);
-                sys::lua_settable(self.lua, -3);
-
-                // index "__gc" call the object's destructor
-                if !self.light {
-                    "__gc".push_to_lua(self.lua);
-
-                    sys::lua_pushcfunction(self.lua, Self::destructor_wrapper);
-
-                    sys::lua_settable(self.lua, -3);
-                } else {
-                    "__gc".push_to_lua(self.lua);
-                    sys::lua_pushcfunction(self.lua, Self::destructor_light_gc);
-                    sys::lua_settable(self.lua, -3);
-                }
-
-                "__index".push_to_lua(self.lua);
-                sys::lua_pushcfunction(self.lua, Self::index_metatable);
-                // sys::lua_newtable(self.lua);
-                sys::lua_rawset(self.lua, -3);
-
-                "__newindex".push_to_lua(self.lua);
-                sys::lua_pushcfunction(self.lua, Self::newindex_metatable);
-                sys::lua_rawset(self.lua, -3);
-
-                sys::lua_setglobal(self.lua, typeid.as_ptr() as *const c_char);
-
-                let typeid = Self::get_metatable_real_key();
-                sys::lua_newtable(self.lua);
-                sys::lua_setglobal(self.lua, typeid.as_ptr());
-                false
-            },
-        }
+        let created = ensure_object_metatable::<T>(self.lua);
+        unsafe { sys::lua_pop(self.lua, 1) };
+        !created
     }
 
     pub fn ensure_table(&mut self) {
@@ -303,8 +334,11 @@ where
         let mut lua = Lua::from_existing_state(self.lua, false);
         if lua.queryc::<LuaTable>(&name).is_none() {
             unsafe {
-                sys::lua_newtable(self.lua);
-                // index "__name" corresponds to the hash of the TypeId of T
+                // the class table is the `__statics` of the metatable, shared with `UserData`
+                if !get_object_table::<T>(self.lua, cstr!("__statics")) {
+                    sys::lua_pop(self.lua, 1);
+                    sys::lua_newtable(self.lua);
+                }
                 "new".push_to_lua(self.lua);
                 if self.light {
                     sys::lua_pushcfunction(self.lua, Self::constructor_light_wrapper);
@@ -315,7 +349,7 @@ where
 
                 "del".push_to_lua(self.lua);
                 if self.light {
-                    sys::lua_pushcfunction(self.lua, Self::delete_light_wrapper);
+                    sys::lua_pushcfunction(self.lua, delete_light_object::<T>);
                 } else {
                     sys::lua_pushcfunction(self.lua, Self::destructor_bad_wrapper);
                 }
@@ -336,13 +370,16 @@ where
     where
         P: LuaPush,
     {
-        let typeid = Self::get_metatable_real_key();
-        match lua.queryc::<LuaTable>(&typeid) {
+        match Self::methods_table(lua) {
             Some(mut table) => {
                 table.set(name, param);
             }
-            None => (),
+            None => return,
         };
+        // a field marked before its getter is added
+        if is_field::<T>(lua.state(), name) {
+            mark_field::<T>(lua.state(), name);
+        }
     }
 
     pub fn add_method_get<P>(&mut self, name: &str, param: P) -> &mut LuaObject<'a, T>
@@ -358,10 +395,9 @@ where
     where
         P: LuaPush,
     {
-        let typeid = Self::get_metatable_real_key();
-        match lua.queryc::<LuaTable>(&typeid) {
+        match Self::setters_table(lua) {
             Some(mut table) => {
-                table.set(Self::get_set_field_key(name), param);
+                table.set(name, param);
             }
             None => (),
         };
@@ -376,8 +412,8 @@ where
         self
     }
 
-    pub fn mark_field(&mut self, name: &'static str) -> &mut LuaObject<'a, T> {
-        Self::set_field(name);
+    pub fn mark_field(&mut self, name: &str) -> &mut LuaObject<'a, T> {
+        mark_field::<T>(self.lua, name);
         self
     }
 
@@ -394,8 +430,7 @@ where
     where
         P: LuaPush,
     {
-        let typeid = Self::get_metatable_real_key();
-        match lua.queryc::<LuaTable>(&typeid) {
+        match Self::methods_table(lua) {
             Some(mut table) => {
                 table.set(name, param);
             }
@@ -403,6 +438,33 @@ where
         };
     }
 
+    /// Sets a metamethod such as `__tostring`, `__add` or `__call`, named by a `MetaMethod` or a
+    /// string. `__index`, `__newindex` and `__gc` are kept for the field dispatch.
+    pub fn meta_def<N, P>(&mut self, name: N, param: P) -> &mut LuaObject<'a, T>
+    where
+        N: AsRef<str>,
+        P: LuaPush,
+    {
+        let mut lua = Lua::from_existing_state(self.lua, false);
+        Self::object_meta_def(&mut lua, name, param);
+        self
+    }
+
+    pub fn object_meta_def<N, P>(lua: &mut Lua, name: N, param: P)
+    where
+        N: AsRef<str>,
+        P: LuaPush,
+    {
+        if is_reserved_meta(name.as_ref()) {
+            warn!("metamethod {} of {} is kept by hclua", name.as_ref(), type_name::<T>());
+            return;
+        }
+        get_type_metatable::<T>(lua.state());
+        if let Some(mut table) = <LuaTable as LuaRead>::lua_read_with_pop(lua.state(), -1, 1) {
+            table.set(name.as_ref(), param);
+        }
+    }
+
     pub fn static_def<P>(&mut self, name: &str, param: P) -> &mut LuaObject<'a, T>
     where
         P: LuaPush,
@@ -433,8 +495,7 @@ where
         name: &str,
         func: extern "C" fn(*mut sys::lua_State) -> libc::c_int,
     ) {
-        let typeid = Self::get_metatable_real_key();
-        match lua.queryc::<LuaTable>(&typeid) {
+        match Self::methods_table(lua) {
             Some(mut table) => {
                 table.register(name, func);
             }
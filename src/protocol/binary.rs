@@ -0,0 +1,198 @@
+use std::{mem, ptr, slice};
+
+use super::options::has_metatable;
+use crate::{impl_box_push, lua_State, sys, LuaPush, LuaRead};
+
+/// Raw bytes kept apart from Lua strings, pushed as a `hclua.binary` userdata.
+///
+/// Scripts see `b:len()` / `#b`, `b:sub(i [, j])` (same indices as `string.sub`)
+/// and `b:tostring()` / `tostring(b)`, two binaries with the same bytes are equal.
+/// `hclua.binary(s)` makes one from a string.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct LuaBinary(pub Vec<u8>);
+
+impl From<Vec<u8>> for LuaBinary {
+    fn from(value: Vec<u8>) -> Self {
+        LuaBinary(value)
+    }
+}
+
+impl From<LuaBinary> for Vec<u8> {
+    fn from(value: LuaBinary) -> Self {
+        value.0
+    }
+}
+
+/// How binary values (`Value::Raw`, serde bytes) are given to Lua.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryMode {
+    /// a plain Lua string, read back as a string when it is valid UTF-8
+    String,
+    /// a `hclua.binary` userdata, read back as binary
+    Tagged,
+}
+
+impl LuaBinary {
+    /// the bytes of the `hclua.binary` userdata at `index`
+    pub fn bytes_at<'a>(lua: *mut lua_State, index: i32) -> Option<&'a [u8]> {
+        unsafe {
+            if sys::lua_type(lua, index) != sys::LUA_TUSERDATA
+                || !has_metatable(lua, index, cstr!("hclua.binary"))
+            {
+                return None;
+            }
+            let data = sys::lua_touserdata(lua, index) as *const Vec<u8>;
+            Some((*data).as_slice())
+        }
+    }
+}
+
+/// pushes `bytes` as a `hclua.binary` userdata
+pub fn push_binary(lua: *mut lua_State, bytes: Vec<u8>) {
+    unsafe {
+        let data = sys::lua_newuserdata(lua, mem::size_of::<Vec<u8>>()) as *mut Vec<u8>;
+        ptr::write(data, bytes);
+        push_binary_mt(lua);
+        sys::lua_setmetatable(lua, -2);
+    }
+}
+
+/// pushes `bytes` following `mode`
+pub(crate) fn push_bytes(lua: *mut lua_State, bytes: &[u8], mode: BinaryMode) {
+    match mode {
+        BinaryMode::String => unsafe {
+            sys::lua_pushlstring(lua, bytes.as_ptr() as *const libc::c_char, bytes.len());
+        },
+        BinaryMode::Tagged => push_binary(lua, bytes.to_vec()),
+    }
+}
+
+fn push_binary_mt(lua: *mut lua_State) {
+    unsafe {
+        if sys::luaL_newmetatable(lua, cstr!("hclua.binary")) == 0 {
+            return;
+        }
+        sys::lua_pushstring(lua, cstr!("hclua.binary"));
+        sys::lua_setfield(lua, -2, cstr!("__name"));
+        sys::lua_pushcfunction(lua, binary_gc);
+        sys::lua_setfield(lua, -2, cstr!("__gc"));
+        sys::lua_pushcfunction(lua, binary_len);
+        sys::lua_setfield(lua, -2, cstr!("__len"));
+        sys::lua_pushcfunction(lua, binary_tostring);
+        sys::lua_setfield(lua, -2, cstr!("__tostring"));
+        sys::lua_pushcfunction(lua, binary_eq);
+        sys::lua_setfield(lua, -2, cstr!("__eq"));
+
+        sys::lua_newtable(lua);
+        sys::lua_pushcfunction(lua, binary_len);
+        sys::lua_setfield(lua, -2, cstr!("len"));
+        sys::lua_pushcfunction(lua, binary_sub);
+        sys::lua_setfield(lua, -2, cstr!("sub"));
+        sys::lua_pushcfunction(lua, binary_tostring);
+        sys::lua_setfield(lua, -2, cstr!("tostring"));
+        sys::lua_setfield(lua, -2, cstr!("__index"));
+    }
+}
+
+fn check_binary<'a>(lua: *mut lua_State, index: i32) -> &'a [u8] {
+    match LuaBinary::bytes_at(lua, index) {
+        Some(bytes) => bytes,
+        None => unsafe {
+            sys::luaL_argerror(lua, index, cstr!("hclua.binary expected"));
+            unreachable!()
+        },
+    }
+}
+
+/// Drops the bytes once: scripts can reach `__gc` through `getmetatable` and call it again, so
+/// the metatable is cleared after the drop and a second call finds no binary.
+extern "C" fn binary_gc(lua: *mut lua_State) -> libc::c_int {
+    if LuaBinary::bytes_at(lua, 1).is_none() {
+        return 0;
+    }
+    unsafe {
+        let data = sys::lua_touserdata(lua, 1) as *mut Vec<u8>;
+        ptr::drop_in_place(data);
+        sys::lua_pushnil(lua);
+        sys::lua_setmetatable(lua, 1);
+    }
+    0
+}
+
+extern "C" fn binary_len(lua: *mut lua_State) -> libc::c_int {
+    let len = check_binary(lua, 1).len();
+    unsafe { sys::lua_pushinteger(lua, len as sys::lua_Integer) };
+    1
+}
+
+extern "C" fn binary_tostring(lua: *mut lua_State) -> libc::c_int {
+    let bytes = check_binary(lua, 1);
+    unsafe { sys::lua_pushlstring(lua, bytes.as_ptr() as *const libc::c_char, bytes.len()) };
+    1
+}
+
+extern "C" fn binary_eq(lua: *mut lua_State) -> libc::c_int {
+    let eq = match (LuaBinary::bytes_at(lua, 1), LuaBinary::bytes_at(lua, 2)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    };
+    unsafe { sys::lua_pushboolean(lua, eq as libc::c_int) };
+    1
+}
+
+/// `b:sub(i [, j])`, a new binary with the bytes from `i` to `j` like `string.sub`
+extern "C" fn binary_sub(lua: *mut lua_State) -> libc::c_int {
+    let bytes = check_binary(lua, 1);
+    let len = bytes.len() as i64;
+    let (i, j) = unsafe {
+        (
+            sys::luaL_checkinteger(lua, 2) as i64,
+            sys::luaL_optinteger(lua, 3, -1) as i64,
+        )
+    };
+    let start = if i < 0 { (len + i + 1).max(1) } else { i.max(1) };
+    let end = if j < 0 { len + j + 1 } else { j.min(len) };
+    let sub = if start > end {
+        Vec::new()
+    } else {
+        bytes[(start - 1) as usize..end as usize].to_vec()
+    };
+    push_binary(lua, sub);
+    1
+}
+
+/// `hclua.binary(s)`, copies the string `s` into a new binary
+pub extern "C" fn lua_binary(lua: *mut lua_State) -> libc::c_int {
+    unsafe {
+        let mut size: libc::size_t = 0;
+        let data = sys::luaL_checklstring(lua, 1, &mut size);
+        let bytes = slice::from_raw_parts(data as *const u8, size).to_vec();
+        push_binary(lua, bytes);
+    }
+    1
+}
+
+impl LuaPush for LuaBinary {
+    fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+        push_binary(lua, self.0);
+        1
+    }
+    impl_box_push!();
+}
+
+/// reads a `hclua.binary` as well as a plain string
+impl LuaRead for LuaBinary {
+    fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop: i32) -> Option<LuaBinary> {
+        if let Some(bytes) = LuaBinary::bytes_at(lua, index) {
+            return Some(LuaBinary(bytes.to_vec()));
+        }
+        if unsafe { sys::lua_type(lua, index) } != sys::LUA_TSTRING {
+            return None;
+        }
+        unsafe {
+            let mut size: libc::size_t = 0;
+            let data = sys::lua_tolstring(lua, index, &mut size);
+            Some(LuaBinary(slice::from_raw_parts(data as *const u8, size).to_vec()))
+        }
+    }
+}
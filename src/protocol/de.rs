@@ -0,0 +1,500 @@
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::Deserialize;
+
+use super::options::{is_null, is_unsupported, TableShape};
+use super::{LuaBinary, SerdeError, SerdeOptions, Unsupported};
+use crate::values::read_integer;
+use crate::{lua_State, sys};
+
+/// Deserializes the Lua value at a stack index, without going through an hcproto buffer.
+///
+/// The inverse of `LuaSerializer`: tables are read as sequences when they have an array part
+/// and as maps otherwise, an enum is read from its name or from a table holding a single
+/// `{ Name = value }` pair. How tables, functions and userdata are treated otherwise
+/// is set by `SerdeOptions`.
+#[derive(Clone, Copy)]
+pub struct LuaDeserializer {
+    lua: *mut lua_State,
+    index: i32,
+    options: SerdeOptions,
+    depth: usize,
+}
+
+impl LuaDeserializer {
+    pub fn new(lua: *mut lua_State, index: i32) -> Self {
+        Self::with_options(lua, index, SerdeOptions::default())
+    }
+
+    pub fn with_options(lua: *mut lua_State, index: i32, options: SerdeOptions) -> Self {
+        Self {
+            lua,
+            index: unsafe { sys::lua_absindex(lua, index) },
+            options,
+            depth: 0,
+        }
+    }
+
+    /// Reads the value at `index`, the stack is the same after the call as before.
+    pub fn from_lua<'de, T: Deserialize<'de>>(lua: *mut lua_State, index: i32) -> Result<T, SerdeError> {
+        Self::from_lua_with(lua, index, &SerdeOptions::default())
+    }
+
+    pub fn from_lua_with<'de, T: Deserialize<'de>>(
+        lua: *mut lua_State,
+        index: i32,
+        options: &SerdeOptions,
+    ) -> Result<T, SerdeError> {
+        let top = unsafe { sys::lua_gettop(lua) };
+        let ret = T::deserialize(LuaDeserializer::with_options(lua, index, *options));
+        unsafe { sys::lua_settop(lua, top) };
+        ret
+    }
+
+    /// a deserializer for a value nested in the current one
+    fn child(&self, index: i32) -> Self {
+        Self {
+            lua: self.lua,
+            index: unsafe { sys::lua_absindex(self.lua, index) },
+            options: self.options,
+            depth: self.depth + 1,
+        }
+    }
+
+    fn skips(&self, index: i32) -> bool {
+        self.options.unsupported == Unsupported::Skip && is_unsupported(self.lua, index)
+    }
+
+    fn lua_type(&self) -> i32 {
+        unsafe { sys::lua_type(self.lua, self.index) }
+    }
+
+    fn type_name(&self) -> String {
+        unsafe {
+            let name = sys::lua_typename(self.lua, self.lua_type());
+            std::ffi::CStr::from_ptr(name).to_string_lossy().to_string()
+        }
+    }
+
+    fn invalid_type(&self, expected: &str) -> SerdeError {
+        SerdeError::new(format!("invalid type: {}, expected {}", self.type_name(), expected))
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe {
+            let mut size: libc::size_t = 0;
+            let data = sys::lua_tolstring(self.lua, self.index, &mut size);
+            std::slice::from_raw_parts(data as *const u8, size)
+        }
+    }
+
+    fn check_stack(&self, size: i32) -> Result<(), SerdeError> {
+        if self.depth >= self.options.max_depth {
+            return Err(SerdeError::new(format!(
+                "table nested deeper than {}",
+                self.options.max_depth
+            )));
+        }
+        if unsafe { sys::lua_checkstack(self.lua, size) } == 0 {
+            return Err(SerdeError::new("lua stack overflow"));
+        }
+        Ok(())
+    }
+
+    fn visit_seq<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        if self.lua_type() != sys::LUA_TTABLE {
+            return Err(self.invalid_type("table"));
+        }
+        self.check_stack(2)?;
+        let len = unsafe { sys::lua_rawlen(self.lua, self.index) };
+        let mut seq = SeqAccess {
+            de: self,
+            next: 1,
+            len,
+        };
+        let value = visitor.visit_seq(&mut seq)?;
+        if seq.next <= len {
+            return Err(de::Error::invalid_length(len, &"fewer elements in table"));
+        }
+        Ok(value)
+    }
+
+    fn visit_map<'de, V: Visitor<'de>>(
+        self,
+        visitor: V,
+        fields: Option<&'static [&'static str]>,
+    ) -> Result<V::Value, SerdeError> {
+        if self.lua_type() != sys::LUA_TTABLE {
+            return Err(self.invalid_type("table"));
+        }
+        self.check_stack(3)?;
+        unsafe { sys::lua_pushnil(self.lua) };
+        let fields = fields.filter(|_| self.options.deny_unknown_fields);
+        visitor.visit_map(MapAccess { de: self, fields })
+    }
+
+    fn visit_integer<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        if self.lua_type() != sys::LUA_TNUMBER {
+            return self.deserialize_any_impl(visitor);
+        }
+        match read_integer(self.lua, self.index) {
+            Some(v) if v >= 0 && v <= u64::MAX as i128 => visitor.visit_u64(v as u64),
+            Some(v) if v < 0 && v >= i64::MIN as i128 => visitor.visit_i64(v as i64),
+            Some(v) => visitor.visit_i128(v),
+            None => self.deserialize_any_impl(visitor),
+        }
+    }
+
+    fn deserialize_any_impl<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        unsafe {
+            match self.lua_type() {
+                sys::LUA_TNIL | sys::LUA_TNONE => visitor.visit_unit(),
+                sys::LUA_TBOOLEAN => visitor.visit_bool(sys::lua_toboolean(self.lua, self.index) != 0),
+                sys::LUA_TNUMBER => {
+                    if sys::lua_isinteger(self.lua, self.index) != 0 {
+                        visitor.visit_i64(sys::lua_tointeger(self.lua, self.index) as i64)
+                    } else {
+                        visitor.visit_f64(sys::lua_tonumber(self.lua, self.index) as f64)
+                    }
+                }
+                sys::LUA_TSTRING => match std::str::from_utf8(self.bytes()) {
+                    Ok(v) => visitor.visit_str(v),
+                    Err(_) => visitor.visit_bytes(self.bytes()),
+                },
+                sys::LUA_TTABLE => match self.options.table_shape(self.lua, self.index) {
+                    TableShape::Array(_) => self.visit_seq(visitor),
+                    TableShape::Map => self.visit_map(visitor, None),
+                },
+                _ if is_null(self.lua, self.index) => visitor.visit_unit(),
+                sys::LUA_TUSERDATA if LuaBinary::bytes_at(self.lua, self.index).is_some() => {
+                    visitor.visit_bytes(LuaBinary::bytes_at(self.lua, self.index).unwrap())
+                }
+                _ if self.options.unsupported == Unsupported::Error => {
+                    Err(SerdeError::new(format!("can't deserialize a {}", self.type_name())))
+                }
+                _ => visitor.visit_unit(),
+            }
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for LuaDeserializer {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_any_impl(visitor)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_any_impl(visitor)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.visit_integer(visitor)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.visit_integer(visitor)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.visit_integer(visitor)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.visit_integer(visitor)
+    }
+
+    fn deserialize_i128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.visit_integer(visitor)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.visit_integer(visitor)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.visit_integer(visitor)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.visit_integer(visitor)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.visit_integer(visitor)
+    }
+
+    fn deserialize_u128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.visit_integer(visitor)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        if self.lua_type() == sys::LUA_TNUMBER {
+            return visitor.visit_f64(unsafe { sys::lua_tonumber(self.lua, self.index) } as f64);
+        }
+        self.deserialize_any_impl(visitor)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_str(visitor)
+    }
+
+    /// numbers are accepted and given in their string form, so integer keys of a
+    /// Lua table can be read into a `HashMap<String, _>`
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        if self.lua_type() == sys::LUA_TNUMBER {
+            let val = unsafe {
+                if sys::lua_isinteger(self.lua, self.index) != 0 {
+                    sys::lua_tointeger(self.lua, self.index).to_string()
+                } else {
+                    sys::lua_tonumber(self.lua, self.index).to_string()
+                }
+            };
+            return visitor.visit_string(val);
+        }
+        self.deserialize_any_impl(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        if self.lua_type() == sys::LUA_TSTRING {
+            return visitor.visit_bytes(self.bytes());
+        }
+        self.deserialize_any_impl(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.lua_type() {
+            sys::LUA_TNIL | sys::LUA_TNONE => visitor.visit_none(),
+            _ if is_null(self.lua, self.index) => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_any_impl(visitor)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.visit_seq(visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        self.visit_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        self.visit_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.visit_map(visitor, None)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        self.visit_map(visitor, Some(fields))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        match self.lua_type() {
+            sys::LUA_TSTRING => {
+                let variant = std::str::from_utf8(self.bytes())
+                    .map_err(|_| SerdeError::new("enum variant must be an UTF-8 string"))?;
+                visitor.visit_enum(variant.into_deserializer())
+            }
+            sys::LUA_TTABLE => {
+                self.check_stack(3)?;
+                unsafe {
+                    sys::lua_pushnil(self.lua);
+                    if sys::lua_next(self.lua, self.index) == 0 {
+                        return Err(SerdeError::new("enum table must have one key, found none"));
+                    }
+                    sys::lua_pushvalue(self.lua, -2);
+                    if sys::lua_next(self.lua, self.index) != 0 {
+                        return Err(SerdeError::new("enum table must have only one key"));
+                    }
+                }
+                // stack is now: key, value
+                let top = unsafe { sys::lua_gettop(self.lua) };
+                visitor.visit_enum(EnumAccess {
+                    key: self.child(top - 1),
+                    value: self.child(top),
+                })
+            }
+            _ => Err(self.invalid_type("string or table")),
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_unit()
+    }
+}
+
+struct SeqAccess {
+    de: LuaDeserializer,
+    next: usize,
+    len: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqAccess {
+    type Error = SerdeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, SerdeError> {
+        let lua = self.de.lua;
+        let top = unsafe { sys::lua_gettop(lua) };
+        loop {
+            if self.next > self.len {
+                return Ok(None);
+            }
+            unsafe { sys::lua_rawgeti(lua, self.de.index, self.next as sys::lua_Integer) };
+            self.next += 1;
+            if !self.de.skips(-1) {
+                break;
+            }
+            unsafe { sys::lua_settop(lua, top) };
+        }
+        let value = seed.deserialize(self.de.child(-1));
+        unsafe { sys::lua_settop(lua, top) };
+        value.map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len + 1 - self.next)
+    }
+}
+
+/// walks the table with `lua_next`, the current key stays on the top of the stack
+/// between two calls to `next_key_seed`
+struct MapAccess {
+    de: LuaDeserializer,
+    /// the fields of the target struct when unknown keys are denied
+    fields: Option<&'static [&'static str]>,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess {
+    type Error = SerdeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, SerdeError> {
+        let lua = self.de.lua;
+        loop {
+            if unsafe { sys::lua_next(lua, self.de.index) } == 0 {
+                return Ok(None);
+            }
+            if !self.de.skips(-1) {
+                break;
+            }
+            unsafe { sys::lua_pop(lua, 1) };
+        }
+        let top = unsafe { sys::lua_gettop(lua) };
+        if let Some(fields) = self.fields {
+            if unsafe { sys::lua_type(lua, -2) } == sys::LUA_TSTRING {
+                let key = self.de.child(-2);
+                let key = String::from_utf8_lossy(key.bytes());
+                if !fields.contains(&&*key) {
+                    return Err(de::Error::unknown_field(&key, fields));
+                }
+            }
+        }
+        let key = seed.deserialize(self.de.child(-2));
+        unsafe { sys::lua_settop(lua, top) };
+        key.map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, SerdeError> {
+        let lua = self.de.lua;
+        let top = unsafe { sys::lua_gettop(lua) };
+        let value = seed.deserialize(self.de.child(-1));
+        // drop the value and whatever the nested reads left, keep the key for `lua_next`
+        unsafe { sys::lua_settop(lua, top - 1) };
+        value
+    }
+}
+
+struct EnumAccess {
+    key: LuaDeserializer,
+    value: LuaDeserializer,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumAccess {
+    type Error = SerdeError;
+    type Variant = VariantAccess;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, VariantAccess), SerdeError> {
+        let variant = seed.deserialize(self.key)?;
+        Ok((variant, VariantAccess { de: self.value }))
+    }
+}
+
+struct VariantAccess {
+    de: LuaDeserializer,
+}
+
+impl<'de> de::VariantAccess<'de> for VariantAccess {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        Deserialize::deserialize(self.de)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, SerdeError> {
+        seed.deserialize(self.de)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        self.de.visit_seq(visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        self.de.visit_map(visitor, Some(fields))
+    }
+}
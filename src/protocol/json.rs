@@ -0,0 +1,90 @@
+use super::{raise_on_error, LuaDeserializer, LuaSerializer, SerdeError, SerdeOptions};
+use crate::{lua_State, sys, Lua};
+
+/// Encodes the value at `index` as JSON, tables are told apart from arrays the same way
+/// as for `WrapSerde` and `SerUtils`, the null sentinel becomes `null`.
+pub fn to_json(
+    lua: *mut lua_State,
+    index: i32,
+    options: &SerdeOptions,
+    pretty: bool,
+) -> Result<String, SerdeError> {
+    let value: serde_json::Value = LuaDeserializer::from_lua_with(lua, index, options)?;
+    let json = if pretty {
+        serde_json::to_string_pretty(&value)
+    } else {
+        serde_json::to_string(&value)
+    };
+    json.map_err(|e| SerdeError::new(e.to_string()))
+}
+
+/// Decodes `json` and pushes it, arrays carry the `hclua.array` metatable and `null`
+/// is nil or the null sentinel following `options.null_sentinel`.
+pub fn from_json(lua: *mut lua_State, json: &str, options: &SerdeOptions) -> Result<i32, SerdeError> {
+    let value: serde_json::Value =
+        serde_json::from_str(json).map_err(|e| SerdeError::new(e.to_string()))?;
+    LuaSerializer::to_lua_with(lua, &value, options)
+}
+
+impl Lua {
+    pub fn to_json(&mut self, index: i32) -> Result<String, SerdeError> {
+        to_json(self.state(), index, &SerdeOptions::default(), false)
+    }
+
+    /// pushes the decoded value on the stack
+    pub fn from_json(&mut self, json: &str) -> Result<i32, SerdeError> {
+        from_json(self.state(), json, &SerdeOptions::default())
+    }
+}
+
+/// reads the boolean field `name` of the options table at `index`
+fn opt_flag(lua: *mut lua_State, index: i32, name: *const libc::c_char) -> bool {
+    unsafe {
+        if sys::lua_type(lua, index) != sys::LUA_TTABLE {
+            return false;
+        }
+        sys::lua_getfield(lua, index, name);
+        let flag = sys::lua_toboolean(lua, -1) != 0;
+        sys::lua_pop(lua, 1);
+        flag
+    }
+}
+
+/// `json.encode(value [, { pretty = true }])`
+extern "C" fn json_encode(lua: *mut lua_State) -> libc::c_int {
+    let pretty = opt_flag(lua, 2, cstr!("pretty"));
+    let ret = to_json(lua, 1, &SerdeOptions::default(), pretty).map(|json| {
+        unsafe { sys::lua_pushlstring(lua, json.as_ptr() as *const libc::c_char, json.len()) };
+        1
+    });
+    raise_on_error(lua, ret.map_err(|e| format!("json.encode: {}", e)))
+}
+
+/// `json.decode(str [, { null = true }])`, with `null` set JSON nulls become `json.null`
+extern "C" fn json_decode(lua: *mut lua_State) -> libc::c_int {
+    let options = SerdeOptions::default().null_sentinel(opt_flag(lua, 2, cstr!("null")));
+    // the argument stays on the stack for the whole call
+    let json = crate::values::read_lstring(lua, 1).and_then(|v| std::str::from_utf8(v).ok());
+    let ret = match json {
+        Some(json) => from_json(lua, json, &options),
+        None => Err(SerdeError::new("UTF-8 string expected")),
+    };
+    raise_on_error(lua, ret.map(|_| 1).map_err(|e| format!("json.decode: {}", e)))
+}
+
+/// Opens the `json` module: `encode`, `decode`, `null` (the null sentinel) and `array`,
+/// register it with `lua.requiref("json", luaopen_json, false)`.
+pub extern "C" fn luaopen_json(lua: *mut lua_State) -> libc::c_int {
+    unsafe {
+        sys::lua_createtable(lua, 0, 4);
+        sys::lua_pushcfunction(lua, json_encode);
+        sys::lua_setfield(lua, -2, cstr!("encode"));
+        sys::lua_pushcfunction(lua, json_decode);
+        sys::lua_setfield(lua, -2, cstr!("decode"));
+        super::push_null(lua);
+        sys::lua_setfield(lua, -2, cstr!("null"));
+        sys::lua_pushcfunction(lua, super::lua_array);
+        sys::lua_setfield(lua, -2, cstr!("array"));
+    }
+    1
+}
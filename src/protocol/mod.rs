@@ -1,11 +1,34 @@
+mod binary;
+mod de;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+mod options;
 mod proto_lua;
+mod schema;
+mod ser;
 mod ser_utils;
+mod stream;
 mod wrapper;
 
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
 
-pub use proto_lua::ProtoLua;
+pub use binary::{lua_binary, push_binary, BinaryMode, LuaBinary};
+pub use de::LuaDeserializer;
+#[cfg(feature = "json")]
+pub use json::{from_json, luaopen_json, to_json};
+#[cfg(feature = "msgpack")]
+pub use msgpack::{luaopen_msgpack, MsgPackLua};
+pub use options::{
+    is_null, load_serde_helpers, lua_array, push_array_mt, push_null, set_array_mt, ArrayMode,
+    EmptyTable, SerdeOptions, Unsupported, ARRAY_MT_NAME,
+};
+pub use proto_lua::{luaopen_proto, ProtoLua};
+pub use schema::{Field, FieldType, Schema};
+pub use ser::LuaSerializer;
 pub use ser_utils::SerUtils;
+pub use stream::{frame_message, split_frame, DecodeError, ProtoDecoder, FRAME_HEADER, MAX_FRAME};
 pub use wrapper::{LuaWrapperTableValue, LuaWrapperValue, LuaWrapperVecValue};
 
 pub struct WrapSerde<T> {
@@ -23,3 +46,53 @@ impl<T> WrapSerde<T> {
         Self { value }
     }
 }
+
+/// Error of `LuaSerializer` and `LuaDeserializer`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerdeError(String);
+
+impl SerdeError {
+    pub fn new<T: Into<String>>(msg: T) -> Self {
+        Self(msg.into())
+    }
+}
+
+impl Display for SerdeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SerdeError {}
+
+/// Returns the count of an `Ok`, an `Err` is copied to the stack, dropped, then raised as a
+/// Lua error.
+///
+/// `lua_error` jumps over the Rust frames and nothing they own is dropped after it, so callers
+/// pass the result as their last step with every other local already dropped.
+pub(crate) fn raise_on_error<E: Display>(
+    lua: *mut crate::lua_State,
+    ret: Result<libc::c_int, E>,
+) -> libc::c_int {
+    let e = match ret {
+        Ok(count) => return count,
+        Err(e) => e,
+    };
+    let msg = e.to_string();
+    drop(e);
+    unsafe { crate::lua_pushlstring(lua, msg.as_ptr() as *const libc::c_char, msg.len()) };
+    drop(msg);
+    unsafe { crate::lua_error(lua) }
+}
+
+impl serde::ser::Error for SerdeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+impl serde::de::Error for SerdeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
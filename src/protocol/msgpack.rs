@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use hcproto::Value;
+use rmpv::Value as MsgValue;
+
+use super::{raise_on_error, LuaWrapperValue, SerUtils, SerdeError, SerdeOptions};
+use crate::{lua_State, sys};
+
+/// MessagePack next to hcproto: the Lua values are read with the same rules as `ProtoLua`
+/// (`SerUtils`), so the same script data can go over either wire format.
+pub struct MsgPackLua;
+
+impl MsgPackLua {
+    /// packs the values from `index` to the top of the stack, one after the other
+    pub fn pack(lua: *mut lua_State, index: i32) -> Result<Vec<u8>, SerdeError> {
+        Self::pack_with(lua, index, &SerdeOptions::default())
+    }
+
+    pub fn pack_with(
+        lua: *mut lua_State,
+        index: i32,
+        options: &SerdeOptions,
+    ) -> Result<Vec<u8>, SerdeError> {
+        let values = SerUtils::lua_convert_value_with(lua, index, options)?;
+        let mut buffer = Vec::new();
+        for value in values {
+            rmpv::encode::write_value(&mut buffer, &to_msgpack(value))
+                .map_err(|e| SerdeError::new(e.to_string()))?;
+        }
+        Ok(buffer)
+    }
+
+    /// pushes every value packed in `data`, returns how many were pushed
+    pub fn unpack(lua: *mut lua_State, data: &[u8]) -> Result<i32, SerdeError> {
+        Self::unpack_with(lua, data, &SerdeOptions::default())
+    }
+
+    pub fn unpack_with(
+        lua: *mut lua_State,
+        mut data: &[u8],
+        options: &SerdeOptions,
+    ) -> Result<i32, SerdeError> {
+        // rmpv counts a value and its array or map body as two levels, a string as three,
+        // bounding it keeps a hostile message from exhausting the Rust stack while decoding
+        let max_depth = options.max_depth.saturating_add(2).saturating_mul(2);
+        let mut values = Vec::new();
+        while !data.is_empty() {
+            let value = rmpv::decode::read_value_with_max_depth(&mut data, max_depth)
+                .map_err(|e| SerdeError::new(e.to_string()))?;
+            values.push(from_msgpack(value)?);
+        }
+        if unsafe { sys::lua_checkstack(lua, values.len() as i32) } == 0 {
+            return Err(SerdeError::new("lua stack overflow"));
+        }
+        let top = unsafe { sys::lua_gettop(lua) };
+        let count = values.len() as i32;
+        for value in values {
+            if let Err(e) = LuaWrapperValue(value).try_push_with_options(lua, options) {
+                unsafe { sys::lua_settop(lua, top) };
+                return Err(e);
+            }
+        }
+        Ok(count)
+    }
+}
+
+fn to_msgpack(value: Value) -> MsgValue {
+    match value {
+        Value::Nil => MsgValue::Nil,
+        Value::Bool(val) => MsgValue::from(val),
+        Value::U8(val) => MsgValue::from(val),
+        Value::I8(val) => MsgValue::from(val),
+        Value::U16(val) => MsgValue::from(val),
+        Value::I16(val) => MsgValue::from(val),
+        Value::U32(val) => MsgValue::from(val),
+        Value::I32(val) => MsgValue::from(val),
+        Value::U64(val) => MsgValue::from(val),
+        Value::I64(val) => MsgValue::from(val),
+        Value::Varint(val) => MsgValue::from(val),
+        Value::F32(val) => MsgValue::from(val),
+        Value::F64(val) => MsgValue::from(val),
+        Value::Str(val) => MsgValue::from(val),
+        Value::Raw(val) => MsgValue::from(val),
+        Value::Map(val) => MsgValue::Map(
+            val.into_iter()
+                .map(|(k, v)| (to_msgpack(k), to_msgpack(v)))
+                .collect(),
+        ),
+        Value::Arr(val) => MsgValue::Array(val.into_iter().map(to_msgpack).collect()),
+    }
+}
+
+fn from_msgpack(value: MsgValue) -> Result<Value, SerdeError> {
+    Ok(match value {
+        MsgValue::Nil => Value::Nil,
+        MsgValue::Boolean(val) => Value::from(val),
+        MsgValue::Integer(val) => match val.as_i64() {
+            Some(val) => Value::from(val),
+            None => Value::U64(val.as_u64().unwrap_or_default()),
+        },
+        MsgValue::F32(val) => Value::from(val),
+        MsgValue::F64(val) => Value::from(val),
+        MsgValue::String(val) => match val.into_str() {
+            Some(val) => Value::Str(val),
+            None => return Err(SerdeError::new("msgpack string isn't valid UTF-8")),
+        },
+        MsgValue::Binary(val) => Value::from(val),
+        MsgValue::Array(val) => {
+            Value::from(val.into_iter().map(from_msgpack).collect::<Result<Vec<_>, _>>()?)
+        }
+        MsgValue::Map(val) => {
+            let mut map = HashMap::with_capacity(val.len());
+            for (k, v) in val {
+                map.insert(from_msgpack(k)?, from_msgpack(v)?);
+            }
+            Value::from(map)
+        }
+        MsgValue::Ext(kind, _) => {
+            return Err(SerdeError::new(format!("unsupported msgpack ext type {}", kind)))
+        }
+    })
+}
+
+/// `msgpack.pack(...)`, all the arguments packed one after the other
+extern "C" fn msgpack_pack(lua: *mut lua_State) -> libc::c_int {
+    if unsafe { sys::lua_gettop(lua) } == 0 {
+        unsafe { sys::lua_pushstring(lua, cstr!("")) };
+        return 1;
+    }
+    let ret = MsgPackLua::pack(lua, 1).map(|data| {
+        unsafe { sys::lua_pushlstring(lua, data.as_ptr() as *const libc::c_char, data.len()) };
+        1
+    });
+    raise_on_error(lua, ret.map_err(|e| format!("msgpack.pack: {}", e)))
+}
+
+/// `msgpack.unpack(s)`, returns every value packed in `s`
+extern "C" fn msgpack_unpack(lua: *mut lua_State) -> libc::c_int {
+    // the argument stays on the stack for the whole call
+    let ret = match crate::values::read_lstring(lua, 1) {
+        Some(data) => MsgPackLua::unpack(lua, data),
+        None => Err(SerdeError::new("string expected")),
+    };
+    raise_on_error(lua, ret.map_err(|e| format!("msgpack.unpack: {}", e)))
+}
+
+/// Opens the `msgpack` module: `pack`, `unpack` and `array`,
+/// register it with `lua.requiref("msgpack", luaopen_msgpack, false)`.
+pub extern "C" fn luaopen_msgpack(lua: *mut lua_State) -> libc::c_int {
+    unsafe {
+        sys::lua_createtable(lua, 0, 3);
+        sys::lua_pushcfunction(lua, msgpack_pack);
+        sys::lua_setfield(lua, -2, cstr!("pack"));
+        sys::lua_pushcfunction(lua, msgpack_unpack);
+        sys::lua_setfield(lua, -2, cstr!("unpack"));
+        sys::lua_pushcfunction(lua, super::lua_array);
+        sys::lua_setfield(lua, -2, cstr!("array"));
+    }
+    1
+}
@@ -0,0 +1,254 @@
+use super::binary::{lua_binary, BinaryMode, LuaBinary};
+use crate::values::read_integer;
+use crate::{lua_State, sys};
+
+/// registry name of the metatable marking a table as an array
+pub const ARRAY_MT_NAME: &str = "hclua.array";
+
+/// How a Lua table is told apart from a map when the target type doesn't say.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArrayMode {
+    /// any table with a border (`#t > 0`) is an array, the hash part is dropped
+    Any,
+    /// only tables whose keys are exactly `1..n`
+    Strict,
+    /// only tables carrying the `hclua.array` metatable, the other modes also honor it
+    Tagged,
+}
+
+/// What an empty table `{}` is read as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmptyTable {
+    Map,
+    Array,
+}
+
+/// What to do with functions, userdata and threads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unsupported {
+    /// read them as nil
+    Nil,
+    /// leave them out of the enclosing table
+    Skip,
+    /// fail the whole conversion
+    Error,
+}
+
+/// Options shared by `SerUtils`, `LuaSerializer` and `LuaDeserializer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SerdeOptions {
+    pub array_mode: ArrayMode,
+    pub empty_table: EmptyTable,
+    /// push `None` and `()` as the null sentinel instead of nil, so arrays keep their holes
+    /// and map entries aren't dropped. The sentinel is always read back as nil.
+    pub null_sentinel: bool,
+    pub unsupported: Unsupported,
+    /// maximum nesting of tables, deeper values are an error
+    pub max_depth: usize,
+    /// fail when a table has a key that isn't a field of the target struct
+    pub deny_unknown_fields: bool,
+    /// how binary values are pushed, a `hclua.binary` is always read back as binary
+    pub binary: BinaryMode,
+}
+
+impl Default for SerdeOptions {
+    fn default() -> Self {
+        Self {
+            array_mode: ArrayMode::Strict,
+            empty_table: EmptyTable::Map,
+            null_sentinel: false,
+            unsupported: Unsupported::Nil,
+            max_depth: 100,
+            deny_unknown_fields: false,
+            binary: BinaryMode::Tagged,
+        }
+    }
+}
+
+pub(crate) enum TableShape {
+    Array(usize),
+    Map,
+}
+
+impl SerdeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn array_mode(mut self, mode: ArrayMode) -> Self {
+        self.array_mode = mode;
+        self
+    }
+
+    pub fn empty_table(mut self, empty: EmptyTable) -> Self {
+        self.empty_table = empty;
+        self
+    }
+
+    pub fn null_sentinel(mut self, null: bool) -> Self {
+        self.null_sentinel = null;
+        self
+    }
+
+    pub fn unsupported(mut self, unsupported: Unsupported) -> Self {
+        self.unsupported = unsupported;
+        self
+    }
+
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    pub fn deny_unknown_fields(mut self, deny: bool) -> Self {
+        self.deny_unknown_fields = deny;
+        self
+    }
+
+    pub fn binary(mut self, mode: BinaryMode) -> Self {
+        self.binary = mode;
+        self
+    }
+
+    /// the shape of the table at `index`, the stack is left untouched
+    pub(crate) fn table_shape(&self, lua: *mut lua_State, index: i32) -> TableShape {
+        unsafe {
+            let index = sys::lua_absindex(lua, index);
+            let len = sys::lua_rawlen(lua, index);
+            if is_tagged_array(lua, index) {
+                return TableShape::Array(len);
+            }
+            let shape = match self.array_mode {
+                ArrayMode::Any if len > 0 => return TableShape::Array(len),
+                ArrayMode::Strict if len > 0 => {
+                    if is_sequence(lua, index, len) {
+                        return TableShape::Array(len);
+                    }
+                    return TableShape::Map;
+                }
+                _ => self.empty_shape(),
+            };
+            sys::lua_pushnil(lua);
+            if sys::lua_next(lua, index) != 0 {
+                sys::lua_pop(lua, 2);
+                return TableShape::Map;
+            }
+            shape
+        }
+    }
+
+    fn empty_shape(&self) -> TableShape {
+        match self.empty_table {
+            EmptyTable::Map => TableShape::Map,
+            EmptyTable::Array => TableShape::Array(0),
+        }
+    }
+}
+
+/// whether the keys of the table are exactly `1..=len`
+unsafe fn is_sequence(lua: *mut lua_State, index: i32, len: usize) -> bool {
+    let mut count = 0;
+    sys::lua_pushnil(lua);
+    while sys::lua_next(lua, index) != 0 {
+        sys::lua_pop(lua, 1);
+        count += 1;
+        let in_range = sys::lua_type(lua, -1) == sys::LUA_TNUMBER
+            && matches!(read_integer(lua, -1), Some(k) if k >= 1 && k <= len as i128);
+        if !in_range || count > len {
+            sys::lua_pop(lua, 1);
+            return false;
+        }
+    }
+    count == len
+}
+
+/// whether the value at `index` has the metatable registered as `name`
+pub(crate) unsafe fn has_metatable(lua: *mut lua_State, index: i32, name: *const libc::c_char) -> bool {
+    if sys::lua_getmetatable(lua, index) == 0 {
+        return false;
+    }
+    sys::luaL_getmetatable(lua, name);
+    let same = sys::lua_rawequal(lua, -1, -2) != 0;
+    sys::lua_pop(lua, 2);
+    same
+}
+
+unsafe fn is_tagged_array(lua: *mut lua_State, index: i32) -> bool {
+    has_metatable(lua, index, cstr!("hclua.array"))
+}
+
+/// pushes the `hclua.array` metatable, creating it the first time
+pub fn push_array_mt(lua: *mut lua_State) {
+    unsafe {
+        if sys::luaL_newmetatable(lua, cstr!("hclua.array")) != 0 {
+            sys::lua_pushstring(lua, cstr!("hclua.array"));
+            sys::lua_setfield(lua, -2, cstr!("__name"));
+        }
+    }
+}
+
+/// marks the table at `index` as an array, so it stays one even when empty
+pub fn set_array_mt(lua: *mut lua_State, index: i32) {
+    unsafe {
+        let index = sys::lua_absindex(lua, index);
+        push_array_mt(lua);
+        sys::lua_setmetatable(lua, index);
+    }
+}
+
+/// `hclua.array([t])`, marks `t` (or a new table) as an array and returns it
+pub extern "C" fn lua_array(lua: *mut lua_State) -> libc::c_int {
+    unsafe {
+        if sys::lua_type(lua, 1) != sys::LUA_TTABLE {
+            sys::lua_newtable(lua);
+        } else {
+            sys::lua_settop(lua, 1);
+        }
+    }
+    set_array_mt(lua, -1);
+    1
+}
+
+/// sets the global `hclua` table with `array`, `array_mt`, `binary` and `null`
+pub fn load_serde_helpers(lua: *mut lua_State) {
+    unsafe {
+        sys::lua_getglobal(lua, cstr!("hclua"));
+        if sys::lua_type(lua, -1) != sys::LUA_TTABLE {
+            sys::lua_pop(lua, 1);
+            sys::lua_newtable(lua);
+            sys::lua_pushvalue(lua, -1);
+            sys::lua_setglobal(lua, cstr!("hclua"));
+        }
+        sys::lua_pushcfunction(lua, lua_array);
+        sys::lua_setfield(lua, -2, cstr!("array"));
+        push_array_mt(lua);
+        sys::lua_setfield(lua, -2, cstr!("array_mt"));
+        sys::lua_pushcfunction(lua, lua_binary);
+        sys::lua_setfield(lua, -2, cstr!("binary"));
+        push_null(lua);
+        sys::lua_setfield(lua, -2, cstr!("null"));
+        sys::lua_pop(lua, 1);
+    }
+}
+
+/// pushes the null sentinel, a light userdata holding a null pointer
+pub fn push_null(lua: *mut lua_State) {
+    unsafe { sys::lua_pushlightuserdata(lua, std::ptr::null_mut()) };
+}
+
+pub fn is_null(lua: *mut lua_State, index: i32) -> bool {
+    unsafe {
+        sys::lua_type(lua, index) == sys::LUA_TLIGHTUSERDATA
+            && sys::lua_touserdata(lua, index).is_null()
+    }
+}
+
+/// functions, userdata (except binaries and the null sentinel) and threads
+pub(crate) fn is_unsupported(lua: *mut lua_State, index: i32) -> bool {
+    match unsafe { sys::lua_type(lua, index) } {
+        sys::LUA_TFUNCTION | sys::LUA_TTHREAD => true,
+        sys::LUA_TUSERDATA => LuaBinary::bytes_at(lua, index).is_none(),
+        sys::LUA_TLIGHTUSERDATA => !is_null(lua, index),
+        _ => false,
+    }
+}
@@ -1,70 +1,247 @@
-use crate::{Lua, LuaPush, LuaWrapperValue};
-use hcproto::Buffer;
+use crate::{LuaPush, LuaRead, LuaWrapperValue};
+use hcproto::{Buffer, Value};
 use log::warn;
+use std::io::Write;
 
-use super::{LuaWrapperTableValue, SerUtils};
+use super::schema::proto_new;
+use super::{
+    frame_message, lua_array, lua_binary, raise_on_error, LuaWrapperTableValue, Schema, SerUtils,
+    SerdeError, SerdeOptions, MAX_FRAME,
+};
 
 pub struct ProtoLua;
 
 impl ProtoLua {
     pub fn pack_protocol(lua: *mut crate::lua_State, index: i32) -> Option<Buffer> {
-        let value = SerUtils::lua_convert_value(lua, index);
-        if value.is_none() {
-            warn!("pack_protocol failed");
-            return None;
+        match Self::try_pack_protocol(lua, index) {
+            Ok(buffer) => Some(buffer),
+            Err(e) => {
+                warn!("pack_protocol failed: {}", e);
+                None
+            }
         }
-        let value = value.unwrap();
+    }
+
+    /// like `pack_protocol`, with the reason when the values can't be packed
+    pub fn try_pack_protocol(lua: *mut crate::lua_State, index: i32) -> Result<Buffer, SerdeError> {
+        let value = SerUtils::lua_convert_value_with(lua, index, &SerdeOptions::default())?;
         let mut buffer = Buffer::new();
-        unwrap_or!(hcproto::encode_msg(&mut buffer, value).ok(), return None);
-        if buffer.len() > 0xFFFFFF {
-            println!("pack message(lua msg) size > 0xFFFF fail!");
-            return None;
-        }
-        Some(buffer)
+        hcproto::encode_msg(&mut buffer, value)
+            .map_err(|e| SerdeError::new(format!("序列化错误:{:?}", e)))?;
+        check_size(buffer)
+    }
+
+    /// like `try_pack_protocol`, with the length header read by `ProtoDecoder`
+    pub fn pack_frame(lua: *mut crate::lua_State, index: i32) -> Result<Vec<u8>, SerdeError> {
+        Self::try_pack_protocol(lua, index).map(|buffer| frame_message(&buffer))
     }
 
     pub fn unpack_protocol(lua: *mut crate::lua_State, buffer: &mut Buffer) -> i32 {
-        if let Ok(val) = hcproto::decode_msg(buffer) {
-            LuaWrapperTableValue(val).push_to_lua(lua);
-            return 1;
-        } else {
-            return 0;
+        match Self::try_unpack_protocol(lua, buffer) {
+            Ok(count) => count,
+            Err(e) => {
+                warn!("unpack_protocol failed: {}", e);
+                0
+            }
         }
     }
 
+    /// like `unpack_protocol`, with the reason when the buffer can't be decoded
+    pub fn try_unpack_protocol(lua: *mut crate::lua_State, buffer: &mut Buffer) -> Result<i32, SerdeError> {
+        let val = hcproto::decode_msg(buffer).map_err(|e| SerdeError::new(format!("{:?}", e)))?;
+        Ok(LuaWrapperTableValue(val).push_to_lua(lua))
+    }
+
+    /// Serializes the table at `index`, a table made by `proto.new(name)` is first
+    /// validated against the registered schema `name`.
+    ///
+    /// Like `pack_protocol` a failure is logged and gives `None`, `try_ser_protocol` returns
+    /// the reason and `proto.encode_map` raises it in scripts.
     pub fn ser_protocol(lua: *mut crate::lua_State, index: i32) -> Option<Buffer> {
-        let t = unsafe { crate::lua_type(lua, index) };
-        if t == crate::LUA_TNIL {
-            return None;
-        }
-        if t != crate::LUA_TTABLE {
-            Lua::lua_error(lua, "类型必须为table");
+        let schema = Schema::name_of(lua, index).and_then(|name| Schema::get(lua, &name));
+        Self::ser_protocol_with(lua, index, schema.as_deref())
+    }
+
+    /// like `ser_protocol`, validating against `schema` when given
+    pub fn ser_protocol_with(
+        lua: *mut crate::lua_State,
+        index: i32,
+        schema: Option<&Schema>,
+    ) -> Option<Buffer> {
+        if unsafe { crate::lua_type(lua, index) } == crate::LUA_TNIL {
             return None;
         }
-        let value = SerUtils::lua_read_value(lua, index, 0);
-        if value.is_none() {
-            warn!("pack_protocol failed");
-            return None;
+        match Self::try_ser_protocol(lua, index, schema) {
+            Ok(buffer) => Some(buffer),
+            Err(e) => {
+                warn!("ser_protocol failed: {}", e);
+                None
+            }
         }
-        let value = value.unwrap();
-        let mut buffer = Buffer::new();
-        if let Err(e) = hcproto::encode_msg_map(&mut buffer, value) {
-            Lua::lua_error(lua, format!("序列化错误:{:?}", e));
-            return None;
+    }
+
+    /// like `ser_protocol`, with the reason instead of `None`
+    pub fn try_ser_protocol(
+        lua: *mut crate::lua_State,
+        index: i32,
+        schema: Option<&Schema>,
+    ) -> Result<Buffer, SerdeError> {
+        if unsafe { crate::lua_type(lua, index) } != crate::LUA_TTABLE {
+            return Err(SerdeError::new("类型必须为table"));
         }
-        if buffer.len() > 0xFFFFFF {
-            Lua::lua_error(lua, "pack message(lua msg) size > 0xFFFF fail!");
-            return None;
+        let value = SerUtils::lua_read_value_with(lua, index, &SerdeOptions::default())?;
+        Self::encode_map(lua, value, schema)
+    }
+
+    /// like `try_ser_protocol`, with the length header read by `ProtoDecoder::new_map`
+    pub fn ser_frame(
+        lua: *mut crate::lua_State,
+        index: i32,
+        schema: Option<&Schema>,
+    ) -> Result<Vec<u8>, SerdeError> {
+        Self::try_ser_protocol(lua, index, schema).map(|buffer| frame_message(&buffer))
+    }
+
+    fn encode_map(
+        lua: *mut crate::lua_State,
+        value: Value,
+        schema: Option<&Schema>,
+    ) -> Result<Buffer, SerdeError> {
+        if let Some(schema) = schema {
+            schema
+                .validate(lua, &value)
+                .map_err(|e| SerdeError::new(format!("协议校验错误:{}", e)))?;
         }
-        Some(buffer)
+        let mut buffer = Buffer::new();
+        hcproto::encode_msg_map(&mut buffer, value)
+            .map_err(|e| SerdeError::new(format!("序列化错误:{:?}", e)))?;
+        check_size(buffer)
     }
 
     pub fn des_protocol(lua: *mut crate::lua_State, buffer: &mut Buffer) -> i32 {
-        if let Ok(val) = hcproto::decode_msg_map(buffer) {
-            LuaWrapperValue(val).push_to_lua(lua);
-            return 1;
-        } else {
-            return 0;
+        match Self::try_des_protocol(lua, buffer) {
+            Ok(count) => count,
+            Err(e) => {
+                warn!("des_protocol failed: {}", e);
+                0
+            }
+        }
+    }
+
+    /// like `des_protocol`, with the reason when the buffer can't be decoded
+    pub fn try_des_protocol(lua: *mut crate::lua_State, buffer: &mut Buffer) -> Result<i32, SerdeError> {
+        let val = hcproto::decode_msg_map(buffer).map_err(|e| SerdeError::new(format!("{:?}", e)))?;
+        LuaWrapperValue(val).try_push_with_options(lua, &SerdeOptions::default())
+    }
+}
+
+fn check_size(buffer: Buffer) -> Result<Buffer, SerdeError> {
+    if buffer.len() > MAX_FRAME {
+        return Err(SerdeError::new("pack message(lua msg) size > 0xFFFFFF fail!"));
+    }
+    Ok(buffer)
+}
+
+fn push_buffer(lua: *mut crate::lua_State, buffer: &Buffer) -> libc::c_int {
+    let data = buffer.get_read_array(buffer.data_len());
+    unsafe { crate::lua_pushlstring(lua, data.as_ptr() as *const libc::c_char, data.len()) };
+    1
+}
+
+/// the string argument at `index` copied into a buffer
+fn read_buffer(lua: *mut crate::lua_State, index: i32) -> Result<Buffer, SerdeError> {
+    let data = crate::values::read_lstring(lua, index);
+    let data = data.ok_or_else(|| SerdeError::new("string expected"))?;
+    let mut buffer = Buffer::new();
+    let _ = buffer.write(data);
+    Ok(buffer)
+}
+
+/// `proto.pack(...)`, the arguments packed into one message string
+extern "C" fn proto_pack(lua: *mut crate::lua_State) -> libc::c_int {
+    let ret = ProtoLua::try_pack_protocol(lua, 1).map(|buffer| push_buffer(lua, &buffer));
+    raise_on_error(lua, ret.map_err(|e| format!("proto.pack: {}", e)))
+}
+
+/// `proto.unpack(s)`, the values packed by `proto.pack`
+extern "C" fn proto_unpack(lua: *mut crate::lua_State) -> libc::c_int {
+    let ret = unpack_values(lua);
+    raise_on_error(lua, ret.map_err(|e| format!("proto.unpack: {}", e)))
+}
+
+/// pushes the values of the message at 1, the values and the buffer are dropped on return
+fn unpack_values(lua: *mut crate::lua_State) -> Result<libc::c_int, SerdeError> {
+    let mut buffer = read_buffer(lua, 1)?;
+    let values =
+        hcproto::decode_msg(&mut buffer).map_err(|e| SerdeError::new(format!("{:?}", e)))?;
+    if unsafe { crate::lua_checkstack(lua, values.len() as i32) } == 0 {
+        return Err(SerdeError::new("too many values"));
+    }
+    let top = unsafe { crate::lua_gettop(lua) };
+    let count = values.len() as libc::c_int;
+    for value in values {
+        if let Err(e) = LuaWrapperValue(value).try_push_with_options(lua, &SerdeOptions::default()) {
+            unsafe { crate::lua_settop(lua, top) };
+            return Err(e);
         }
     }
+    Ok(count)
+}
+
+/// `proto.encode_map(t)`, checked against the schema of `t` when made by `proto.new`
+extern "C" fn proto_encode_map(lua: *mut crate::lua_State) -> libc::c_int {
+    let ret = {
+        let schema = Schema::name_of(lua, 1).and_then(|name| Schema::get(lua, &name));
+        ProtoLua::try_ser_protocol(lua, 1, schema.as_deref()).map(|buffer| push_buffer(lua, &buffer))
+    };
+    raise_on_error(lua, ret.map_err(|e| format!("proto.encode_map: {}", e)))
+}
+
+/// `proto.decode_map(s)`
+extern "C" fn proto_decode_map(lua: *mut crate::lua_State) -> libc::c_int {
+    let ret = read_buffer(lua, 1).and_then(|mut buffer| ProtoLua::try_des_protocol(lua, &mut buffer));
+    raise_on_error(lua, ret.map_err(|e| format!("proto.decode_map: {}", e)))
+}
+
+/// `proto.validate(t [, name])`, checks `t` against its schema (or the message `name`),
+/// returns `true` or `false` and the path of the first bad field
+extern "C" fn proto_validate(lua: *mut crate::lua_State) -> libc::c_int {
+    let name: Option<String> = LuaRead::lua_read_at_position(lua, 2);
+    let ret = match name.or_else(|| Schema::name_of(lua, 1)).and_then(|name| Schema::get(lua, &name)) {
+        Some(schema) => match SerUtils::lua_read_value_with(lua, 1, &SerdeOptions::default()) {
+            Ok(value) => schema.validate(lua, &value),
+            Err(e) => Err(e),
+        },
+        None => Err(SerdeError::new("unknown message")),
+    };
+    match ret {
+        Ok(()) => true.push_to_lua(lua),
+        Err(e) => (false, e.to_string()).push_to_lua(lua),
+    }
+}
+
+/// Opens the `hclua.proto` module: `pack(...)`, `unpack(s)`, `encode_map(t)`, `decode_map(s)`,
+/// `new(name)`, `validate(t [, name])`, `array([t])` and `binary(s)`. Failures raise Lua errors.
+/// Register it with `lua.requiref("hclua.proto", luaopen_proto, false)`.
+pub extern "C" fn luaopen_proto(lua: *mut crate::lua_State) -> libc::c_int {
+    unsafe {
+        crate::lua_createtable(lua, 0, 8);
+        crate::lua_pushcfunction(lua, proto_pack);
+        crate::lua_setfield(lua, -2, cstr!("pack"));
+        crate::lua_pushcfunction(lua, proto_unpack);
+        crate::lua_setfield(lua, -2, cstr!("unpack"));
+        crate::lua_pushcfunction(lua, proto_encode_map);
+        crate::lua_setfield(lua, -2, cstr!("encode_map"));
+        crate::lua_pushcfunction(lua, proto_decode_map);
+        crate::lua_setfield(lua, -2, cstr!("decode_map"));
+        crate::lua_pushcfunction(lua, lua_array);
+        crate::lua_setfield(lua, -2, cstr!("array"));
+        crate::lua_pushcfunction(lua, lua_binary);
+        crate::lua_setfield(lua, -2, cstr!("binary"));
+        crate::lua_pushcfunction(lua, proto_new);
+        crate::lua_setfield(lua, -2, cstr!("new"));
+        crate::lua_pushcfunction(lua, proto_validate);
+        crate::lua_setfield(lua, -2, cstr!("validate"));
+    }
+    1
 }
@@ -0,0 +1,564 @@
+use std::sync::Arc;
+
+use hcproto::Value;
+use serde::Serialize;
+
+use super::{set_array_mt, LuaSerializer, LuaWrapperValue, SerUtils, SerdeError, SerdeOptions};
+use crate::userdata::{push_userdata, read_userdata};
+use crate::{lua_State, sys, Lua, LuaPush};
+
+/// registry field of the table holding the schemas registered in a state
+const SCHEMAS_KEY: &str = "hclua.proto.schemas";
+
+/// Type of a message field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldType {
+    Any,
+    Bool,
+    Integer,
+    /// an integer or a float
+    Number,
+    Str,
+    /// a string or a binary
+    Binary,
+    Array(Box<FieldType>),
+    /// a table with any keys and values of the given type
+    Map(Box<FieldType>),
+    /// a nested message declared inline
+    Struct(Schema),
+    /// a nested message registered under that name
+    Message(String),
+}
+
+impl FieldType {
+    /// Parses the declaration form used by `Schema::declare`: `any`, `bool`, `integer`,
+    /// `number`, `string`, `binary`, `[type]` for an array, `{type}` for a map and
+    /// the name of a registered message otherwise.
+    pub fn parse(ty: &str) -> FieldType {
+        let ty = ty.trim();
+        if let Some(inner) = ty.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            return FieldType::Array(Box::new(FieldType::parse(inner)));
+        }
+        if let Some(inner) = ty.strip_prefix('{').and_then(|t| t.strip_suffix('}')) {
+            return FieldType::Map(Box::new(FieldType::parse(inner)));
+        }
+        match ty {
+            "any" => FieldType::Any,
+            "bool" | "boolean" => FieldType::Bool,
+            "integer" | "int" => FieldType::Integer,
+            "number" | "float" => FieldType::Number,
+            "string" | "str" => FieldType::Str,
+            "binary" | "bytes" => FieldType::Binary,
+            name => FieldType::Message(name.to_string()),
+        }
+    }
+
+    /// the type of an example value
+    fn infer(value: &Value) -> FieldType {
+        match value {
+            Value::Nil => FieldType::Any,
+            Value::Bool(_) => FieldType::Bool,
+            Value::F32(_) | Value::F64(_) => FieldType::Number,
+            Value::Str(_) => FieldType::Str,
+            Value::Raw(_) => FieldType::Binary,
+            Value::Arr(val) => FieldType::Array(Box::new(
+                val.first().map(FieldType::infer).unwrap_or(FieldType::Any),
+            )),
+            Value::Map(val) => match Schema::from_value("", value) {
+                Ok(schema) if !val.is_empty() => FieldType::Struct(schema),
+                _ => FieldType::Map(Box::new(FieldType::Any)),
+            },
+            _ => FieldType::Integer,
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            FieldType::Any => "any".to_string(),
+            FieldType::Bool => "bool".to_string(),
+            FieldType::Integer => "integer".to_string(),
+            FieldType::Number => "number".to_string(),
+            FieldType::Str => "string".to_string(),
+            FieldType::Binary => "binary".to_string(),
+            FieldType::Array(ty) => format!("[{}]", ty.name()),
+            FieldType::Map(ty) => format!("{{{}}}", ty.name()),
+            FieldType::Struct(schema) => format!("{{{}}}", schema.name),
+            FieldType::Message(name) => name.clone(),
+        }
+    }
+
+    fn validate(&self, lua: *mut lua_State, value: &Value, path: &str) -> Result<(), SerdeError> {
+        let ok = match (self, value) {
+            (FieldType::Any, _) => true,
+            (FieldType::Bool, Value::Bool(_)) => true,
+            (FieldType::Integer, Value::F32(_) | Value::F64(_)) => false,
+            (FieldType::Integer | FieldType::Number, v) => is_number(v),
+            (FieldType::Str, Value::Str(_)) => true,
+            (FieldType::Binary, Value::Str(_) | Value::Raw(_)) => true,
+            (FieldType::Array(ty), Value::Arr(val)) => {
+                for (i, v) in val.iter().enumerate() {
+                    ty.validate(lua, v, &format!("{}[{}]", path, i + 1))?;
+                }
+                true
+            }
+            // an empty table not tagged as an array
+            (FieldType::Array(_), Value::Map(val)) => val.is_empty(),
+            (FieldType::Map(ty), Value::Map(val)) => {
+                for (k, v) in val {
+                    ty.validate(lua, v, &format!("{}[{}]", path, key_name(k)))?;
+                }
+                true
+            }
+            (FieldType::Struct(schema), Value::Map(_)) => {
+                return schema.validate_at(lua, value, path)
+            }
+            (FieldType::Message(name), Value::Map(_)) => {
+                let schema = Schema::get(lua, name).ok_or_else(|| {
+                    SerdeError::new(format!("{}: unknown message {}", path, name))
+                })?;
+                return schema.validate_at(lua, value, path);
+            }
+            _ => false,
+        };
+        if !ok {
+            return Err(SerdeError::new(format!(
+                "{}: expected {}, found {}",
+                path,
+                self.name(),
+                value_name(value)
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub ty: FieldType,
+    pub required: bool,
+    /// the value `proto.new` fills the field with
+    pub default: Option<Value>,
+}
+
+/// The fields of a protocol message, checked by `ProtoLua::ser_protocol` for the tables made
+/// by `proto.new(name)` and by `ProtoLua::ser_protocol_with`.
+///
+/// Schemas are registered per state, messages refer to each other by name within the state
+/// they were registered in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schema {
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+impl Schema {
+    pub fn new<T: Into<String>>(name: T) -> Self {
+        Schema {
+            name: name.into(),
+            fields: Vec::new(),
+        }
+    }
+
+    /// a required field
+    pub fn field<T: Into<String>>(mut self, name: T, ty: FieldType) -> Self {
+        self.fields.push(Field {
+            name: name.into(),
+            ty,
+            required: true,
+            default: None,
+        });
+        self
+    }
+
+    pub fn optional<T: Into<String>>(mut self, name: T, ty: FieldType) -> Self {
+        self.fields.push(Field {
+            name: name.into(),
+            ty,
+            required: false,
+            default: None,
+        });
+        self
+    }
+
+    /// a field `proto.new` fills with `default`
+    pub fn default_field<T: Into<String>>(
+        mut self,
+        name: T,
+        ty: FieldType,
+        default: Value,
+    ) -> Self {
+        self.fields.push(Field {
+            name: name.into(),
+            ty,
+            required: true,
+            default: Some(default),
+        });
+        self
+    }
+
+    /// Declares a message from `(name, type)` pairs, see `FieldType::parse` for the types,
+    /// a trailing `?` makes the field optional: `[("name", "string"), ("guild", "integer?")]`.
+    pub fn declare<T: Into<String>>(name: T, fields: &[(&str, &str)]) -> Self {
+        let mut schema = Schema::new(name);
+        for (field, ty) in fields {
+            schema = match ty.strip_suffix('?') {
+                Some(ty) => schema.optional(*field, FieldType::parse(ty)),
+                None => schema.field(*field, FieldType::parse(ty)),
+            };
+        }
+        schema
+    }
+
+    /// Infers the fields from an example value, its values become the defaults (arrays
+    /// default to empty, their first element gives the element type), `None` fields are
+    /// optional and accept anything.
+    ///
+    /// The example goes through a scratch state, pushed by `LuaSerializer` and read back by
+    /// `SerUtils`, so it is seen exactly as the same data coming from a script.
+    pub fn from_serde<N: Into<String>, T: Serialize>(
+        name: N,
+        example: &T,
+    ) -> Result<Self, SerdeError> {
+        let mut lua = Lua::new();
+        // `None` fields stay in the table as the sentinel, read back as nil entries
+        let options = SerdeOptions::new().null_sentinel(true);
+        LuaSerializer::to_lua_with(lua.state(), example, &options)?;
+        let example = SerUtils::lua_read_value_with(lua.state(), -1, &options)?;
+        drop(lua);
+        Self::from_value(name, &example)
+    }
+
+    pub fn from_value<N: Into<String>>(name: N, example: &Value) -> Result<Self, SerdeError> {
+        let name = name.into();
+        let map = match example {
+            Value::Map(map) => map,
+            _ => {
+                return Err(SerdeError::new(format!(
+                    "{}: example must be a table",
+                    name
+                )))
+            }
+        };
+        let mut fields = Vec::with_capacity(map.len());
+        for (k, v) in map {
+            let field = match k {
+                Value::Str(field) => field.clone(),
+                _ => {
+                    return Err(SerdeError::new(format!(
+                        "{}: field names must be strings",
+                        name
+                    )))
+                }
+            };
+            let required = *v != Value::Nil;
+            let default = match v {
+                Value::Nil => None,
+                Value::Arr(_) => Some(Value::Arr(Vec::new())),
+                v => Some(v.clone()),
+            };
+            fields.push(Field {
+                name: field,
+                ty: FieldType::infer(v),
+                required,
+                default,
+            });
+        }
+        fields.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Schema { name, fields })
+    }
+
+    /// Registers the schema in the state under its name, replacing a previous one.
+    ///
+    /// Message and field names must not contain NUL bytes.
+    pub fn register(self, lua: *mut lua_State) -> Result<(), SerdeError> {
+        self.check_names()?;
+        unsafe {
+            push_schemas(lua);
+            push_str(lua, &self.name);
+            push_userdata(Arc::new(self), lua, |_| {});
+            sys::lua_rawset(lua, -3);
+            sys::lua_pop(lua, 1);
+        }
+        Ok(())
+    }
+
+    /// the schema registered as `name` in the state
+    pub fn get(lua: *mut lua_State, name: &str) -> Option<Arc<Schema>> {
+        unsafe {
+            push_schemas(lua);
+            push_str(lua, name);
+            sys::lua_rawget(lua, -2);
+            let schema = read_userdata::<Arc<Schema>>(lua, -1).map(|schema| schema.clone());
+            sys::lua_pop(lua, 2);
+            schema
+        }
+    }
+
+    fn check_names(&self) -> Result<(), SerdeError> {
+        if self.name.contains('\0') {
+            return Err(SerdeError::new(format!(
+                "{:?}: message name contains NUL",
+                self.name
+            )));
+        }
+        for field in &self.fields {
+            if field.name.contains('\0') {
+                return Err(SerdeError::new(format!(
+                    "{}.{:?}: field name contains NUL",
+                    self.name, field.name
+                )));
+            }
+            let mut ty = &field.ty;
+            while let FieldType::Array(inner) | FieldType::Map(inner) = ty {
+                ty = inner;
+            }
+            match ty {
+                FieldType::Struct(schema) => schema.check_names()?,
+                FieldType::Message(name) if name.contains('\0') => {
+                    return Err(SerdeError::new(format!(
+                        "{}.{}: message name contains NUL",
+                        self.name, field.name
+                    )))
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks `value`, the error names the path of the first bad field, `Login.items[2].id`.
+    ///
+    /// Fields of a message type are checked against the schemas registered in `lua`.
+    pub fn validate(&self, lua: *mut lua_State, value: &Value) -> Result<(), SerdeError> {
+        self.validate_at(lua, value, &self.name)
+    }
+
+    fn validate_at(
+        &self,
+        lua: *mut lua_State,
+        value: &Value,
+        path: &str,
+    ) -> Result<(), SerdeError> {
+        let map = match value {
+            Value::Map(map) => map,
+            _ => {
+                return Err(SerdeError::new(format!(
+                    "{}: expected {}, found {}",
+                    path,
+                    self.name,
+                    value_name(value)
+                )))
+            }
+        };
+        for field in &self.fields {
+            let field_path = format!("{}.{}", path, field.name);
+            match map.get(&Value::Str(field.name.clone())) {
+                Some(v) => field.ty.validate(lua, v, &field_path)?,
+                None if field.required => {
+                    return Err(SerdeError::new(format!("{}: missing field", field_path)))
+                }
+                None => (),
+            }
+        }
+        for k in map.keys() {
+            let known = match k {
+                Value::Str(k) => self.fields.iter().any(|f| &f.name == k),
+                _ => false,
+            };
+            if !known {
+                return Err(SerdeError::new(format!(
+                    "{}.{}: unknown field",
+                    path,
+                    key_name(k)
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Pushes a new table holding the defaults, tagged with the schema.
+    ///
+    /// Required fields of a message type without a default are filled with a new table of
+    /// that message, a message requiring itself can't be made and is an error.
+    pub fn push_new(&self, lua: *mut lua_State) -> Result<(), SerdeError> {
+        let top = unsafe { sys::lua_gettop(lua) };
+        let mut making = Vec::new();
+        let ret = self.push_new_at(lua, &mut making);
+        if ret.is_err() {
+            unsafe { sys::lua_settop(lua, top) };
+        }
+        ret
+    }
+
+    /// `making` holds the names of the messages being made around this one
+    fn push_new_at(&self, lua: *mut lua_State, making: &mut Vec<String>) -> Result<(), SerdeError> {
+        if !self.name.is_empty() && making.contains(&self.name) {
+            return Err(SerdeError::new(format!(
+                "{}: message requires itself",
+                making.join(".")
+            )));
+        }
+        // the table, a field name and its value, or the metatable being created
+        if unsafe { sys::lua_checkstack(lua, 4) } == 0 {
+            return Err(SerdeError::new("lua stack overflow"));
+        }
+        making.push(self.name.clone());
+        unsafe { sys::lua_createtable(lua, 0, self.fields.len() as i32) };
+        for field in &self.fields {
+            push_str(lua, &field.name);
+            let pushed = match (&field.default, &field.ty) {
+                (Some(default), _) => {
+                    LuaWrapperValue(default.clone())
+                        .try_push_with_options(lua, &SerdeOptions::default())?;
+                    true
+                }
+                (None, _) if !field.required => false,
+                (None, FieldType::Struct(schema)) => {
+                    schema.push_new_at(lua, making)?;
+                    true
+                }
+                (None, FieldType::Message(name)) => match Schema::get(lua, name) {
+                    Some(schema) => {
+                        schema.push_new_at(lua, making)?;
+                        true
+                    }
+                    None => false,
+                },
+                (None, FieldType::Array(_)) => {
+                    unsafe { sys::lua_newtable(lua) };
+                    set_array_mt(lua, -1);
+                    true
+                }
+                (None, FieldType::Map(_)) => {
+                    unsafe { sys::lua_newtable(lua) };
+                    true
+                }
+                _ => false,
+            };
+            unsafe {
+                if pushed {
+                    sys::lua_rawset(lua, -3);
+                } else {
+                    sys::lua_pop(lua, 1);
+                }
+            }
+        }
+        making.pop();
+        if !self.name.is_empty() {
+            self.push_mt(lua);
+            unsafe { sys::lua_setmetatable(lua, -2) };
+        }
+        Ok(())
+    }
+
+    /// the metatable marking the tables of this message, `__schema` holds its name
+    fn push_mt(&self, lua: *mut lua_State) {
+        unsafe {
+            push_str(lua, &format!("hclua.proto.{}", self.name));
+            sys::lua_pushvalue(lua, -1);
+            sys::lua_rawget(lua, sys::LUA_REGISTRYINDEX);
+            if sys::lua_type(lua, -1) == sys::LUA_TTABLE {
+                sys::lua_remove(lua, -2);
+                return;
+            }
+            sys::lua_pop(lua, 1);
+            sys::lua_createtable(lua, 0, 1);
+            self.name.as_str().push_to_lua(lua);
+            sys::lua_setfield(lua, -2, cstr!("__schema"));
+            // registry[name] = mt, leaving mt on the stack
+            sys::lua_pushvalue(lua, -1);
+            sys::lua_insert(lua, -3);
+            sys::lua_rawset(lua, sys::LUA_REGISTRYINDEX);
+        }
+    }
+
+    /// the name of the schema the table at `index` was made with by `proto.new`
+    pub fn name_of(lua: *mut lua_State, index: i32) -> Option<String> {
+        unsafe {
+            if sys::lua_type(lua, index) != sys::LUA_TTABLE
+                || sys::lua_getmetatable(lua, index) == 0
+            {
+                return None;
+            }
+            sys::lua_getfield(lua, -1, cstr!("__schema"));
+            let name: Option<String> = crate::LuaRead::lua_read_at_position(lua, -1);
+            sys::lua_pop(lua, 2);
+            name
+        }
+    }
+}
+
+fn is_number(value: &Value) -> bool {
+    matches!(
+        value,
+        Value::U8(_)
+            | Value::I8(_)
+            | Value::U16(_)
+            | Value::I16(_)
+            | Value::U32(_)
+            | Value::I32(_)
+            | Value::U64(_)
+            | Value::I64(_)
+            | Value::Varint(_)
+            | Value::F32(_)
+            | Value::F64(_)
+    )
+}
+
+fn value_name(value: &Value) -> &'static str {
+    match value {
+        Value::Nil => "nil",
+        Value::Bool(_) => "bool",
+        Value::F32(_) | Value::F64(_) => "number",
+        Value::Str(_) => "string",
+        Value::Raw(_) => "binary",
+        Value::Map(_) => "table",
+        Value::Arr(_) => "array",
+        _ => "integer",
+    }
+}
+
+fn key_name(key: &Value) -> String {
+    match key {
+        Value::Str(k) => k.clone(),
+        Value::U32(k) => k.to_string(),
+        Value::I64(k) => k.to_string(),
+        Value::F64(k) => k.to_string(),
+        Value::Bool(k) => k.to_string(),
+        k => value_name(k).to_string(),
+    }
+}
+
+/// pushes the table of the schemas registered in the state, creating it the first time
+unsafe fn push_schemas(lua: *mut lua_State) {
+    push_str(lua, SCHEMAS_KEY);
+    sys::lua_rawget(lua, sys::LUA_REGISTRYINDEX);
+    if sys::lua_type(lua, -1) == sys::LUA_TTABLE {
+        return;
+    }
+    sys::lua_pop(lua, 1);
+    sys::lua_newtable(lua);
+    push_str(lua, SCHEMAS_KEY);
+    sys::lua_pushvalue(lua, -2);
+    sys::lua_rawset(lua, sys::LUA_REGISTRYINDEX);
+}
+
+fn push_str(lua: *mut lua_State, value: &str) {
+    unsafe { sys::lua_pushlstring(lua, value.as_ptr() as *const libc::c_char, value.len()) };
+}
+
+/// `proto.new(name)`, a table holding the defaults of the registered message `name`
+pub extern "C" fn proto_new(lua: *mut lua_State) -> libc::c_int {
+    let ret = {
+        let name: Option<String> = crate::LuaRead::lua_read_at_position(lua, 1);
+        match name.as_deref().and_then(|name| Schema::get(lua, name)) {
+            Some(schema) => schema.push_new(lua),
+            None => Err(SerdeError::new(format!(
+                "unknown message {}",
+                name.unwrap_or_default()
+            ))),
+        }
+    };
+    super::raise_on_error(lua, ret.map(|()| 1).map_err(|e| format!("proto.new: {}", e)))
+}
@@ -0,0 +1,459 @@
+use serde::ser::{self, Serialize};
+
+use super::binary::push_bytes;
+use super::{push_null, set_array_mt, SerdeError, SerdeOptions};
+use crate::{lua_State, sys, LuaPush};
+
+/// Serializes a value straight onto the Lua stack, without going through an hcproto buffer.
+///
+/// Every `serialize_*` call pushes exactly one value:
+/// structs and maps become tables, sequences and tuples become arrays tagged with the
+/// `hclua.array` metatable (so an empty one reads back as an array), `None` and `()` become
+/// `nil`, a unit variant becomes its name and the other variants become a table with the
+/// variant name as the only key, `{ Name = value }`.
+#[derive(Clone, Copy)]
+pub struct LuaSerializer {
+    lua: *mut lua_State,
+    options: SerdeOptions,
+    depth: usize,
+}
+
+impl LuaSerializer {
+    pub fn new(lua: *mut lua_State) -> Self {
+        Self::with_options(lua, SerdeOptions::default())
+    }
+
+    pub fn with_options(lua: *mut lua_State, options: SerdeOptions) -> Self {
+        Self {
+            lua,
+            options,
+            depth: 0,
+        }
+    }
+
+    /// Pushes `value` and returns the number of pushed values (always 1),
+    /// the stack is left untouched on error.
+    pub fn to_lua<T: Serialize + ?Sized>(lua: *mut lua_State, value: &T) -> Result<i32, SerdeError> {
+        Self::to_lua_with(lua, value, &SerdeOptions::default())
+    }
+
+    pub fn to_lua_with<T: Serialize + ?Sized>(
+        lua: *mut lua_State,
+        value: &T,
+        options: &SerdeOptions,
+    ) -> Result<i32, SerdeError> {
+        let top = unsafe { sys::lua_gettop(lua) };
+        match value.serialize(LuaSerializer::with_options(lua, *options)) {
+            Ok(()) => Ok(1),
+            Err(e) => {
+                unsafe { sys::lua_settop(lua, top) };
+                Err(e)
+            }
+        }
+    }
+
+    fn child(&self) -> Self {
+        Self {
+            depth: self.depth + 1,
+            ..*self
+        }
+    }
+
+    fn check_stack(&self, size: i32) -> Result<(), SerdeError> {
+        if self.depth >= self.options.max_depth {
+            return Err(SerdeError::new(format!(
+                "table nested deeper than {}",
+                self.options.max_depth
+            )));
+        }
+        if unsafe { sys::lua_checkstack(self.lua, size) } == 0 {
+            return Err(SerdeError::new("lua stack overflow"));
+        }
+        Ok(())
+    }
+
+    fn push<T: LuaPush>(&self, value: T) -> Result<(), SerdeError> {
+        value.push_to_lua(self.lua);
+        Ok(())
+    }
+
+    /// integers that don't fit in a `lua_Integer` are refused, a float would lose digits
+    /// and a string would change the type of the value
+    fn push_integer<T>(&self, value: T) -> Result<(), SerdeError>
+    where
+        T: TryInto<sys::lua_Integer> + std::fmt::Display + Copy,
+    {
+        match value.try_into() {
+            Ok(v) => self.push(v),
+            Err(_) => Err(SerdeError::new(format!(
+                "integer {} out of range of a Lua integer",
+                value
+            ))),
+        }
+    }
+
+    fn push_str(&self, value: &str) {
+        unsafe {
+            sys::lua_pushlstring(self.lua, value.as_ptr() as *const libc::c_char, value.len())
+        };
+    }
+
+    fn new_table(&self, narr: usize, nrec: usize) -> Result<i32, SerdeError> {
+        self.check_stack(3)?;
+        unsafe {
+            sys::lua_createtable(self.lua, narr as i32, nrec as i32);
+            Ok(sys::lua_gettop(self.lua))
+        }
+    }
+
+    /// starts `{ variant = ... }`, the inner value is set by `end_variant`
+    fn begin_variant(&self, variant: &'static str) -> Result<(), SerdeError> {
+        self.new_table(0, 1)?;
+        self.push_str(variant);
+        Ok(())
+    }
+
+    fn end_variant(&self) {
+        unsafe { sys::lua_rawset(self.lua, -3) };
+    }
+}
+
+pub struct SerializeTable {
+    ser: LuaSerializer,
+    table: i32,
+    len: sys::lua_Integer,
+    variant: bool,
+}
+
+impl SerializeTable {
+    fn new(ser: LuaSerializer, table: i32, variant: bool) -> Self {
+        Self {
+            ser,
+            table,
+            len: 0,
+            variant,
+        }
+    }
+
+    fn push_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        value.serialize(self.ser.child())?;
+        self.len += 1;
+        unsafe { sys::lua_rawseti(self.ser.lua, self.table, self.len) };
+        Ok(())
+    }
+
+    fn set_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), SerdeError> {
+        self.ser.push_str(key);
+        value.serialize(self.ser.child())?;
+        unsafe { sys::lua_rawset(self.ser.lua, self.table) };
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        if self.variant {
+            self.ser.end_variant();
+        }
+        Ok(())
+    }
+}
+
+impl ser::Serializer for LuaSerializer {
+    type Ok = ();
+    type Error = SerdeError;
+
+    type SerializeSeq = SerializeTable;
+    type SerializeTuple = SerializeTable;
+    type SerializeTupleStruct = SerializeTable;
+    type SerializeTupleVariant = SerializeTable;
+    type SerializeMap = SerializeTable;
+    type SerializeStruct = SerializeTable;
+    type SerializeStructVariant = SerializeTable;
+
+    fn serialize_bool(self, v: bool) -> Result<(), SerdeError> {
+        self.push(v)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), SerdeError> {
+        self.push(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), SerdeError> {
+        self.push(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), SerdeError> {
+        self.push(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), SerdeError> {
+        self.push(v)
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), SerdeError> {
+        self.push_integer(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), SerdeError> {
+        self.push(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), SerdeError> {
+        self.push(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), SerdeError> {
+        self.push(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), SerdeError> {
+        self.push_integer(v)
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), SerdeError> {
+        self.push_integer(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), SerdeError> {
+        self.push(v)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), SerdeError> {
+        self.push(v)
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), SerdeError> {
+        self.push_str(v.encode_utf8(&mut [0; 4]));
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), SerdeError> {
+        self.push_str(v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), SerdeError> {
+        push_bytes(self.lua, v, self.options.binary);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), SerdeError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), SerdeError> {
+        if self.options.null_sentinel {
+            push_null(self.lua);
+        } else {
+            unsafe { sys::lua_pushnil(self.lua) };
+        }
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), SerdeError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), SerdeError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.begin_variant(variant)?;
+        value.serialize(self.child())?;
+        self.end_variant();
+        Ok(())
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeTable, SerdeError> {
+        let table = self.new_table(len.unwrap_or(0), 0)?;
+        set_array_mt(self.lua, table);
+        Ok(SerializeTable::new(self, table, false))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeTable, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeTable, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeTable, SerdeError> {
+        self.begin_variant(variant)?;
+        let table = self.new_table(len, 0)?;
+        set_array_mt(self.lua, table);
+        Ok(SerializeTable::new(self, table, true))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeTable, SerdeError> {
+        let table = self.new_table(0, len.unwrap_or(0))?;
+        Ok(SerializeTable::new(self, table, false))
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeTable, SerdeError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeTable, SerdeError> {
+        self.begin_variant(variant)?;
+        let table = self.new_table(0, len)?;
+        Ok(SerializeTable::new(self, table, true))
+    }
+}
+
+impl ser::SerializeSeq for SerializeTable {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        SerializeTable::end(self)
+    }
+}
+
+impl ser::SerializeTuple for SerializeTable {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        SerializeTable::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeTable {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        SerializeTable::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeTable {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        SerializeTable::end(self)
+    }
+}
+
+impl ser::SerializeMap for SerializeTable {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+        key.serialize(self.ser.child())?;
+        let lua = self.ser.lua;
+        unsafe {
+            let invalid = match sys::lua_type(lua, -1) {
+                sys::LUA_TNIL => true,
+                sys::LUA_TNUMBER => sys::lua_tonumber(lua, -1).is_nan(),
+                _ => false,
+            };
+            if invalid {
+                sys::lua_pop(lua, 1);
+                return Err(SerdeError::new("map key can't be nil or NaN"));
+            }
+        }
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        value.serialize(self.ser.child())?;
+        unsafe { sys::lua_rawset(self.ser.lua, self.table) };
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        SerializeTable::end(self)
+    }
+}
+
+impl ser::SerializeStruct for SerializeTable {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.set_field(key, value)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        SerializeTable::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeTable {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.set_field(key, value)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        SerializeTable::end(self)
+    }
+}
@@ -1,7 +1,13 @@
-use crate::{lua_State, LuaRead};
+use crate::values::read_integer;
+use crate::lua_State;
 use hcproto::Value;
+use log::warn;
 use std::collections::HashMap;
-use std::{ptr, str};
+use std::ffi::CStr;
+use std::ptr;
+
+use super::options::{is_null, is_unsupported, TableShape};
+use super::{LuaBinary, SerdeError, SerdeOptions, Unsupported};
 
 pub struct SerUtils;
 
@@ -21,96 +27,170 @@ impl SerUtils {
     }
 
     pub fn lua_read_value(lua: *mut lua_State, index: i32, stack: u32) -> Option<Value> {
-        if stack > 100 {
-            return None;
-        };
+        let options = SerdeOptions::default();
+        let depth = stack as usize;
+        match Self::read_value(lua, index, &options, depth) {
+            Ok(value) => Some(value.unwrap_or(Value::Nil)),
+            Err(e) => {
+                warn!("lua_read_value failed: {}", e);
+                None
+            }
+        }
+    }
+
+    /// reads the value at `index` following `options`
+    pub fn lua_read_value_with(
+        lua: *mut lua_State,
+        index: i32,
+        options: &SerdeOptions,
+    ) -> Result<Value, SerdeError> {
+        Ok(Self::read_value(lua, index, options, 0)?.unwrap_or(Value::Nil))
+    }
+
+    /// `Ok(None)` when the value is skipped by `Unsupported::Skip`
+    fn read_value(
+        lua: *mut lua_State,
+        index: i32,
+        options: &SerdeOptions,
+        depth: usize,
+    ) -> Result<Option<Value>, SerdeError> {
         unsafe {
             let t = crate::lua_type(lua, index);
             let value = match t {
-                crate::LUA_TBOOLEAN => {
-                    let val: bool =
-                        unwrap_or!(LuaRead::lua_read_at_position(lua, index), return None);
-                    Some(Value::from(val))
-                }
-                crate::LUA_TNUMBER => {
-                    let val: f64 =
-                        unwrap_or!(LuaRead::lua_read_at_position(lua, index), return None);
-                    if val - val.floor() > 0.00001 {
-                        Some(Value::from(val as f64))
-                    } else {
-                        Some(Value::from(val as i64))
-                    }
+                crate::LUA_TNIL | crate::LUA_TNONE => Value::Nil,
+                crate::LUA_TBOOLEAN => Value::from(crate::lua_toboolean(lua, index) != 0),
+                // a float keeps its type even with an integral value, `3.0` stays a float
+                crate::LUA_TNUMBER if crate::lua_isinteger(lua, index) != 0 => {
+                    Value::from(crate::lua_tointeger(lua, index) as i64)
                 }
+                crate::LUA_TNUMBER => Value::from(crate::lua_tonumber(lua, index) as f64),
                 crate::LUA_TSTRING => {
-                    let mut dst = unwrap_or!(Self::read_str_to_vec(lua, index), return None);
-
-                    if dst.len() > 4
-                        && dst[0] == 140
-                        && dst[1] == 150
-                        && dst[2] == 141
-                        && dst[3] == 151
-                    {
-                        dst.drain(0..4);
-                        return Some(Value::from(dst));
-                    }
-
-                    if let Some(val) = str::from_utf8(&dst).ok() {
-                        Some(Value::Str(val.to_string()))
-                    } else {
-                        Some(Value::from(dst))
+                    let dst = unwrap_or!(
+                        Self::read_str_to_vec(lua, index),
+                        return Err(SerdeError::new("invalid string"))
+                    );
+                    match String::from_utf8(dst) {
+                        Ok(val) => Value::Str(val),
+                        Err(e) => Value::from(e.into_bytes()),
                     }
                 }
                 crate::LUA_TTABLE => {
-                    if !crate::lua_istable(lua, index) {
-                        return None;
+                    if depth >= options.max_depth {
+                        return Err(SerdeError::new(format!(
+                            "table nested deeper than {}",
+                            options.max_depth
+                        )));
+                    }
+                    if crate::lua_checkstack(lua, 3) == 0 {
+                        return Err(SerdeError::new("lua stack overflow"));
                     }
-                    let len = crate::lua_rawlen(lua, index);
-                    if len > 0 {
-                        let mut val: Vec<Value> = Vec::new();
-                        for i in 1..(len + 1) {
-                            crate::lua_pushnumber(lua, i as f64);
-                            let new_index = if index < 0 { index - 1 } else { index };
-                            crate::lua_gettable(lua, new_index);
-                            let sub_val = SerUtils::lua_read_value(lua, -1, stack + 1);
-                            if sub_val.is_none() {
-                                return None;
+                    let index = crate::lua_absindex(lua, index);
+                    match options.table_shape(lua, index) {
+                        TableShape::Array(len) => {
+                            let mut val: Vec<Value> = Vec::with_capacity(len);
+                            for i in 1..(len + 1) {
+                                crate::lua_rawgeti(lua, index, i as crate::lua_Integer);
+                                let sub_val = Self::read_value(lua, -1, options, depth + 1);
+                                crate::lua_pop(lua, 1);
+                                if let Some(sub_val) = sub_val? {
+                                    val.push(sub_val);
+                                }
                             }
-                            val.push(sub_val.unwrap());
-                            crate::lua_pop(lua, 1);
+                            Value::from(val)
                         }
-                        Some(Value::from(val))
-                    } else {
-                        let mut val: HashMap<Value, Value> = HashMap::new();
-                        crate::lua_pushnil(lua);
-                        let t = if index < 0 { index - 1 } else { index };
-
-                        while crate::lua_istable(lua, t) && crate::lua_next(lua, t) != 0 {
-                            let sub_val = unwrap_or!(
-                                SerUtils::lua_read_value(lua, -1, stack + 1),
-                                return None
-                            );
-                            let value = if crate::lua_isnumber(lua, -2) != 0 {
-                                let idx: u32 =
-                                    unwrap_or!(LuaRead::lua_read_at_position(lua, -2), return None);
-                                Value::from(idx)
-                            } else {
-                                let key: String =
-                                    unwrap_or!(LuaRead::lua_read_at_position(lua, -2), return None);
-                                Value::from(key)
-                            };
-                            val.insert(value, sub_val);
-                            crate::lua_pop(lua, 1);
+                        TableShape::Map => {
+                            let mut val: HashMap<Value, Value> = HashMap::new();
+                            crate::lua_pushnil(lua);
+                            while crate::lua_next(lua, index) != 0 {
+                                let entry = Self::read_key(lua, -2, options, depth + 1).and_then(|key| {
+                                    match (key, Self::read_value(lua, -1, options, depth + 1)?) {
+                                        (Some(key), Some(sub_val)) => Ok(Some((key, sub_val))),
+                                        _ => Ok(None),
+                                    }
+                                });
+                                match entry {
+                                    Ok(Some((key, sub_val))) => {
+                                        val.insert(key, sub_val);
+                                    }
+                                    Ok(None) => (),
+                                    Err(e) => {
+                                        crate::lua_pop(lua, 2);
+                                        return Err(e);
+                                    }
+                                }
+                                crate::lua_pop(lua, 1);
+                            }
+                            Value::from(val)
                         }
-                        Some(Value::from(val))
                     }
                 }
-                _ => Some(Value::Nil),
+                _ if is_null(lua, index) => Value::Nil,
+                crate::LUA_TUSERDATA if LuaBinary::bytes_at(lua, index).is_some() => {
+                    Value::from(LuaBinary::bytes_at(lua, index).unwrap().to_vec())
+                }
+                _ => match options.unsupported {
+                    Unsupported::Nil => Value::Nil,
+                    Unsupported::Skip => return Ok(None),
+                    Unsupported::Error => {
+                        let name = CStr::from_ptr(crate::lua_typename(lua, t));
+                        return Err(SerdeError::new(format!(
+                            "can't serialize a {}",
+                            name.to_string_lossy()
+                        )));
+                    }
+                },
             };
-            value
+            Ok(Some(value))
+        }
+    }
+
+    /// Reads a table key, with a stable type for each kind of Lua key: integers fitting an `u32`
+    /// stay `U32` as the protocol always sent them, other integers are `I64`, fractional
+    /// numbers `F64`, and booleans, strings, binaries and tables keep their own type.
+    /// `Ok(None)` when the key is skipped by `Unsupported::Skip`.
+    fn read_key(
+        lua: *mut lua_State,
+        index: i32,
+        options: &SerdeOptions,
+        depth: usize,
+    ) -> Result<Option<Value>, SerdeError> {
+        unsafe {
+            match crate::lua_type(lua, index) {
+                crate::LUA_TNUMBER => Ok(Some(match read_integer(lua, index) {
+                    Some(key) if key >= 0 && key <= u32::MAX as i128 => Value::from(key as u32),
+                    Some(key) if key >= i64::MIN as i128 && key <= i64::MAX as i128 => {
+                        Value::from(key as i64)
+                    }
+                    _ => Value::from(crate::lua_tonumber(lua, index) as f64),
+                })),
+                _ if is_unsupported(lua, index) && options.unsupported != Unsupported::Skip => {
+                    let name = CStr::from_ptr(crate::lua_typename(lua, crate::lua_type(lua, index)));
+                    Err(SerdeError::new(format!(
+                        "can't use a {} as map key",
+                        name.to_string_lossy()
+                    )))
+                }
+                _ => Self::read_value(lua, index, options, depth),
+            }
         }
     }
 
     pub fn lua_convert_value(lua: *mut lua_State, index: i32) -> Option<Vec<Value>> {
+        match Self::lua_convert_value_with(lua, index, &SerdeOptions::default()) {
+            Ok(val) => Some(val),
+            Err(e) => {
+                warn!("lua_convert_value failed: {}", e);
+                None
+            }
+        }
+    }
+
+    /// reads every value from `index` to the top of the stack (or the last `-index` values)
+    pub fn lua_convert_value_with(
+        lua: *mut lua_State,
+        index: i32,
+        options: &SerdeOptions,
+    ) -> Result<Vec<Value>, SerdeError> {
         let size = if index < 0 {
             -index
         } else {
@@ -120,13 +200,11 @@ impl SerUtils {
 
         let mut val: Vec<Value> = Vec::new();
         for i in 0..size {
-            let sub_val =
-                SerUtils::lua_read_value(lua, if is_neg { index - i } else { i + index }, 0);
-            if sub_val.is_none() {
-                return None;
+            let index = if is_neg { index - i } else { i + index };
+            if let Some(sub_val) = SerUtils::read_value(lua, index, options, 0)? {
+                val.push(sub_val);
             }
-            val.push(sub_val.unwrap());
         }
-        Some(val)
+        Ok(val)
     }
 }
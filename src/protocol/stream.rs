@@ -0,0 +1,226 @@
+use std::fmt::Display;
+use std::io::Write;
+
+use hcproto::{Buffer, Value};
+
+use super::{LuaWrapperTableValue, LuaWrapperValue, SerdeOptions};
+use crate::{lua_State, LuaPush};
+
+/// size of the big-endian length put in front of every framed message
+pub const FRAME_HEADER: usize = 4;
+/// the largest message `ProtoLua` packs
+pub const MAX_FRAME: usize = 0xFFFFFF;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// the next message isn't complete, at least `needed` more bytes must be fed
+    NeedMore { needed: usize },
+    /// the byte at `offset` in the stream can't be decoded
+    Corrupt { offset: usize, reason: String },
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::NeedMore { needed } => write!(f, "need {} more bytes", needed),
+            DecodeError::Corrupt { offset, reason } => {
+                write!(f, "corrupt message at offset {}: {}", offset, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Puts the length header in front of a packed message, the framing read by `ProtoDecoder`.
+///
+/// `ProtoLua::pack_frame` and `ProtoLua::ser_frame` pack and frame in one step.
+pub fn frame_message(buffer: &Buffer) -> Vec<u8> {
+    let data = buffer.get_read_array(buffer.data_len());
+    let mut frame = Vec::with_capacity(FRAME_HEADER + data.len());
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Incremental decoder for framed hcproto messages coming from a socket.
+///
+/// Bytes are given as they arrive with `feed`, then `push_next` decodes and pushes one message
+/// at a time, a `NeedMore` error only means the next message hasn't fully arrived yet.
+/// Messages are decoded in place in the decoder's buffer, a buffer already read from the
+/// socket can be handed over with `feed_buffer` without copying it.
+/// A message that fails to decode is skipped, so the following ones can still be read,
+/// an oversized length header drops the bytes pending at that time.
+pub struct ProtoDecoder {
+    buffer: Buffer,
+    /// stream offset of the first byte of `buffer`
+    offset: usize,
+    /// decode `ser_protocol` maps instead of `pack_protocol` lists
+    map: bool,
+}
+
+impl ProtoDecoder {
+    /// decoder for messages framed by `ProtoLua::pack_frame`
+    pub fn new() -> Self {
+        Self {
+            buffer: Buffer::new(),
+            offset: 0,
+            map: false,
+        }
+    }
+
+    /// decoder for messages framed by `ProtoLua::ser_frame`
+    pub fn new_map() -> Self {
+        Self {
+            map: true,
+            ..Self::new()
+        }
+    }
+
+    /// appends the bytes read from the stream
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.compact();
+        let _ = self.buffer.write(bytes);
+    }
+
+    /// Appends a buffer read from the stream, its unread bytes are taken without copying
+    /// when nothing is pending.
+    pub fn feed_buffer(&mut self, buffer: Buffer) {
+        self.compact();
+        if self.pending() == 0 && buffer.get_rpos() <= self.position() {
+            // the read position of the new buffer is where the stream continues
+            self.offset = self.position() - buffer.get_rpos();
+            self.buffer = buffer;
+        } else {
+            let _ = self.buffer.write(buffer.get_read_array(buffer.data_len()));
+        }
+    }
+
+    /// drops the decoded bytes once they outweigh the pending ones
+    fn compact(&mut self) {
+        let rpos = self.buffer.get_rpos();
+        if rpos == 0 || rpos < self.pending() {
+            return;
+        }
+        let mut buffer = Buffer::new();
+        let _ = buffer.write(self.buffer.get_read_array(self.pending()));
+        self.offset += rpos;
+        self.buffer = buffer;
+    }
+
+    /// bytes fed but not decoded yet
+    pub fn pending(&self) -> usize {
+        self.buffer.data_len()
+    }
+
+    /// stream offset of the next byte to read
+    fn position(&self) -> usize {
+        self.offset + self.buffer.get_rpos()
+    }
+
+    /// Decodes the next message in place, the read position is moved past its frame.
+    fn decode_frame<T, E, F>(&mut self, decode: F) -> Result<T, DecodeError>
+    where
+        E: std::fmt::Debug,
+        F: FnOnce(&mut Buffer) -> Result<T, E>,
+    {
+        let data = self.buffer.get_read_array(self.pending());
+        let used = match split_frame(data, self.position()).map(|(_, used)| used) {
+            Ok(used) => used,
+            Err(e @ DecodeError::Corrupt { .. }) => {
+                // a broken header can't be resynchronized, drop what is pending
+                self.buffer.set_rpos(self.buffer.get_wpos());
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        let start = self.buffer.get_rpos() + FRAME_HEADER;
+        let end = self.buffer.get_rpos() + used;
+        self.buffer.set_rpos(start);
+        let ret = decode(&mut self.buffer);
+        // the byte the decoder stopped at, where it failed or where the message ended
+        let stop = self.buffer.get_rpos();
+        self.buffer.set_rpos(end);
+        match ret {
+            Err(e) => Err(corrupt(self.offset + stop.min(end), e)),
+            Ok(_) if stop != end => Err(DecodeError::Corrupt {
+                offset: self.offset + stop.min(end),
+                reason: format!(
+                    "message body is {} bytes, {} were decoded",
+                    end - start,
+                    stop - start
+                ),
+            }),
+            Ok(value) => Ok(value),
+        }
+    }
+
+    /// decodes the next message without pushing it
+    pub fn decode_next(&mut self) -> Result<Vec<Value>, DecodeError> {
+        if self.map {
+            self.decode_frame(|buffer| hcproto::decode_msg_map(buffer).map(|value| vec![value]))
+        } else {
+            self.decode_frame(hcproto::decode_msg)
+        }
+    }
+
+    /// Decodes the next message and pushes it like `unpack_protocol` (a table of the values)
+    /// or `des_protocol` (the map), returns the number of pushed values.
+    ///
+    /// A decoded map that can't be pushed (see `LuaWrapperValue::try_push_with_options`) is
+    /// reported as corrupt at the start of its message.
+    pub fn push_next(&mut self, lua: *mut lua_State) -> Result<i32, DecodeError> {
+        let start = self.position();
+        let mut values = self.decode_next()?;
+        if self.map {
+            let value = LuaWrapperValue(values.pop().unwrap_or(Value::Nil));
+            value
+                .try_push_with_options(lua, &SerdeOptions::default())
+                .map_err(|e| DecodeError::Corrupt {
+                    offset: start,
+                    reason: e.to_string(),
+                })
+        } else {
+            Ok(LuaWrapperTableValue(values).push_to_lua(lua))
+        }
+    }
+}
+
+impl Default for ProtoDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Splits one framed message off `data` without copying it, `offset` is the stream offset of
+/// `data[0]` for error reports. Returns the message body and the number of bytes it used.
+pub fn split_frame(data: &[u8], offset: usize) -> Result<(&[u8], usize), DecodeError> {
+    if data.len() < FRAME_HEADER {
+        return Err(DecodeError::NeedMore {
+            needed: FRAME_HEADER - data.len(),
+        });
+    }
+    let mut header = [0; FRAME_HEADER];
+    header.copy_from_slice(&data[..FRAME_HEADER]);
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_FRAME {
+        return Err(DecodeError::Corrupt {
+            offset,
+            reason: format!("message size {} > {}", len, MAX_FRAME),
+        });
+    }
+    let end = FRAME_HEADER + len;
+    if data.len() < end {
+        return Err(DecodeError::NeedMore {
+            needed: end - data.len(),
+        });
+    }
+    Ok((&data[FRAME_HEADER..end], end))
+}
+
+fn corrupt<E: std::fmt::Debug>(offset: usize, e: E) -> DecodeError {
+    DecodeError::Corrupt {
+        offset,
+        reason: format!("{:?}", e),
+    }
+}
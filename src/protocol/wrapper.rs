@@ -1,8 +1,10 @@
-use std::collections::HashMap;
-use libc;
-use std::hash::Hash;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use hcproto::Value;
+use log::warn;
 use crate::{impl_box_push, lua_State, LuaPush};
+use super::binary::push_bytes;
+use super::{set_array_mt, SerdeError, SerdeOptions};
 /// the wrapper for push to lua
 
 #[derive(PartialEq, Clone)]
@@ -12,51 +14,166 @@ impl Eq for LuaWrapperValue {
 }
 
 impl Hash for LuaWrapperValue {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        core::mem::discriminant(&self.0).hash(state);
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_value(&self.0, state);
     }
 }
 
-impl LuaPush for LuaWrapperValue {
-    fn push_to_lua(self, lua: *mut lua_State) -> i32 {
-        match self.0 {
-            Value::Nil => ().push_to_lua(lua),
-            Value::Bool(val) => val.push_to_lua(lua),
-            Value::U8(val) => val.push_to_lua(lua),
-            Value::I8(val) => val.push_to_lua(lua),
-            Value::U16(val) => val.push_to_lua(lua),
-            Value::I16(val) => val.push_to_lua(lua),
-            Value::U32(val) => val.push_to_lua(lua),
-            Value::I32(val) => val.push_to_lua(lua),
-            Value::U64(val) => val.push_to_lua(lua),
-            Value::I64(val) => val.push_to_lua(lua),
-            Value::Varint(val) => val.push_to_lua(lua),
-            Value::F32(val) => val.push_to_lua(lua),
-            Value::F64(val) => val.push_to_lua(lua),
-            Value::Str(val) => val.push_to_lua(lua),
-            Value::Raw(val) => {
-                unsafe {
-                    let mut pre = vec![140, 150, 141, 151];
-                    pre.extend(val);
-                    crate::lua_pushlstring(lua, pre.as_ptr() as *const libc::c_char, pre.len())
-                };
-                1
+/// hashes the content and not only the variant, so maps with many keys don't
+/// end up in a single bucket
+fn hash_value<H: Hasher>(value: &Value, state: &mut H) {
+    core::mem::discriminant(value).hash(state);
+    match value {
+        Value::Nil => (),
+        Value::Bool(val) => val.hash(state),
+        Value::U8(val) => val.hash(state),
+        Value::I8(val) => val.hash(state),
+        Value::U16(val) => val.hash(state),
+        Value::I16(val) => val.hash(state),
+        Value::U32(val) => val.hash(state),
+        Value::I32(val) => val.hash(state),
+        Value::U64(val) => val.hash(state),
+        Value::I64(val) => val.hash(state),
+        Value::Varint(val) => val.hash(state),
+        // 0.0 and -0.0 are equal, so they must hash the same
+        Value::F32(val) => (if *val == 0.0 { 0 } else { val.to_bits() }).hash(state),
+        Value::F64(val) => (if *val == 0.0 { 0 } else { val.to_bits() }).hash(state),
+        Value::Str(val) => val.hash(state),
+        Value::Raw(val) => val.hash(state),
+        Value::Map(val) => {
+            // the iteration order of a map isn't stable, combine the entries independently of it
+            let mut sum: u64 = 0;
+            for (k, v) in val {
+                let mut hasher = DefaultHasher::new();
+                hash_value(k, &mut hasher);
+                hash_value(v, &mut hasher);
+                sum = sum.wrapping_add(hasher.finish());
+            }
+            val.len().hash(state);
+            sum.hash(state);
+        }
+        Value::Arr(val) => {
+            val.len().hash(state);
+            for v in val {
+                hash_value(v, state);
             }
-            Value::Map(mut val) => {
-                let mut wrapper_val: HashMap<LuaWrapperValue, LuaWrapperValue> = HashMap::new();
-                for (k, v) in val.drain() {
-                    wrapper_val.insert(LuaWrapperValue(k), LuaWrapperValue(v));
-                }
-                wrapper_val.push_to_lua(lua)
+        }
+    }
+}
+
+impl LuaWrapperValue {
+    /// Pushes the value, binaries follow `options.binary`.
+    ///
+    /// Tables nested deeper than `options.max_depth` and map keys Lua can't index with
+    /// (`nil`, NaN) are refused before anything is set, the stack is left untouched on error.
+    pub fn try_push_with_options(
+        self,
+        lua: *mut lua_State,
+        options: &SerdeOptions,
+    ) -> Result<i32, SerdeError> {
+        let top = unsafe { crate::lua_gettop(lua) };
+        match push_value(lua, self.0, options, 0) {
+            Ok(()) => Ok(1),
+            Err(e) => {
+                unsafe { crate::lua_settop(lua, top) };
+                Err(e)
+            }
+        }
+    }
+
+    /// like `try_push_with_options`, a value that can't be pushed becomes `nil`
+    pub fn push_with_options(self, lua: *mut lua_State, options: &SerdeOptions) -> i32 {
+        match self.try_push_with_options(lua, options) {
+            Ok(count) => count,
+            Err(e) => {
+                warn!("LuaWrapperValue push failed: {}", e);
+                ().push_to_lua(lua)
+            }
+        }
+    }
+}
+
+fn check_key(key: &Value) -> Result<(), SerdeError> {
+    match key {
+        Value::Nil => Err(SerdeError::new("map key is nil")),
+        Value::F32(val) if val.is_nan() => Err(SerdeError::new("map key is NaN")),
+        Value::F64(val) if val.is_nan() => Err(SerdeError::new("map key is NaN")),
+        _ => Ok(()),
+    }
+}
+
+fn new_table(
+    lua: *mut lua_State,
+    options: &SerdeOptions,
+    depth: usize,
+    narr: usize,
+    nrec: usize,
+) -> Result<(), SerdeError> {
+    if depth >= options.max_depth {
+        return Err(SerdeError::new(format!(
+            "table nested deeper than {}",
+            options.max_depth
+        )));
+    }
+    // the table, a key and a value, or the table and the array metatable being created
+    if unsafe { crate::lua_checkstack(lua, 4) } == 0 {
+        return Err(SerdeError::new("lua stack overflow"));
+    }
+    unsafe { crate::lua_createtable(lua, narr as i32, nrec as i32) };
+    Ok(())
+}
+
+fn push_value(
+    lua: *mut lua_State,
+    value: Value,
+    options: &SerdeOptions,
+    depth: usize,
+) -> Result<(), SerdeError> {
+    match value {
+        Value::Nil => ().push_to_lua(lua),
+        Value::Bool(val) => val.push_to_lua(lua),
+        Value::U8(val) => val.push_to_lua(lua),
+        Value::I8(val) => val.push_to_lua(lua),
+        Value::U16(val) => val.push_to_lua(lua),
+        Value::I16(val) => val.push_to_lua(lua),
+        Value::U32(val) => val.push_to_lua(lua),
+        Value::I32(val) => val.push_to_lua(lua),
+        Value::U64(val) => val.try_push_to_lua(lua).map_err(SerdeError::new)?,
+        Value::I64(val) => val.push_to_lua(lua),
+        Value::Varint(val) => val.push_to_lua(lua),
+        Value::F32(val) => val.push_to_lua(lua),
+        Value::F64(val) => val.push_to_lua(lua),
+        Value::Str(val) => val.push_to_lua(lua),
+        Value::Raw(val) => {
+            push_bytes(lua, &val, options.binary);
+            1
+        }
+        Value::Map(val) => {
+            new_table(lua, options, depth, 0, val.len())?;
+            for (k, v) in val {
+                check_key(&k)?;
+                push_value(lua, k, options, depth + 1)?;
+                push_value(lua, v, options, depth + 1)?;
+                unsafe { crate::lua_rawset(lua, -3) };
             }
-            Value::Arr(mut val) => {
-                let mut wrapper_val: Vec<LuaWrapperValue> = vec![];
-                for v in val.drain(..) {
-                    wrapper_val.push(LuaWrapperValue(v));
-                }
-                wrapper_val.push_to_lua(lua)
+            1
+        }
+        Value::Arr(val) => {
+            new_table(lua, options, depth, val.len(), 0)?;
+            for (i, v) in val.into_iter().enumerate() {
+                push_value(lua, v, options, depth + 1)?;
+                unsafe { crate::lua_rawseti(lua, -2, (i + 1) as crate::lua_Integer) };
             }
+            set_array_mt(lua, -1);
+            1
         }
+    };
+    Ok(())
+}
+
+impl LuaPush for LuaWrapperValue {
+    fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+        self.push_with_options(lua, &SerdeOptions::default())
     }
     impl_box_push!();
 }
@@ -1,7 +1,7 @@
 
-use crate::{ impl_box_push, lua_State, sys, LuaPush, LuaRead, LuaTable};
+use crate::{ impl_box_push, lua_State, sys, LuaPush, LuaRead};
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::hash::Hash;
 
 fn push_iter<V, I>(lua: *mut lua_State, iterator: I) -> i32
@@ -11,40 +11,32 @@ fn push_iter<V, I>(lua: *mut lua_State, iterator: I) -> i32
     unsafe { sys::lua_newtable(lua) };
 
     for (elem, index) in iterator.zip(1..) {
-        let size = elem.push_to_lua(lua);
-
-        match size {
-            0 => continue,
-            1 => {
-                let index = index as u32;
-                index.push_to_lua(lua);
-                unsafe { sys::lua_insert(lua, -2) }
-                unsafe { sys::lua_settable(lua, -3) }
-            },
-            2 => unsafe { sys::lua_settable(lua, -3) },
-            _ => unreachable!()
+        if elem.push_packed_to_lua(lua) == 0 {
+            continue;
         }
+        unsafe { sys::lua_rawseti(lua, -2, index) }
     }
 
     1
 }
 
-fn push_rec_iter<V, I>(lua: *mut lua_State, iterator: I) -> i32
-                          where V: LuaPush, I: Iterator<Item=V>
+fn push_rec_iter<K, V, I>(lua: *mut lua_State, iterator: I) -> i32
+                          where K: LuaPush, V: LuaPush, I: Iterator<Item=(K, V)>
 {
     let (nrec, _) = iterator.size_hint();
 
     // creating empty table with pre-allocated non-array elements
     unsafe { sys::lua_createtable(lua, 0, nrec as i32) };
 
-    for elem in iterator {
-        let size = elem.push_to_lua(lua);
-
-        match size {
-            0 => continue,
-            2 => unsafe { sys::lua_settable(lua, -3) },
-            _ => unreachable!()
+    for (key, value) in iterator {
+        if key.push_packed_to_lua(lua) == 0 {
+            continue;
+        }
+        if value.push_packed_to_lua(lua) == 0 {
+            unsafe { sys::lua_pop(lua, 1) };
+            continue;
         }
+        unsafe { sys::lua_settable(lua, -3) }
     }
 
     1
@@ -64,6 +56,20 @@ impl<'a, T> LuaPush for &'a [T] where T: Clone + LuaPush {
     impl_box_push!();
 }
 
+impl<T> LuaPush for VecDeque<T> where T: LuaPush {
+    fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+        push_iter(lua, self.into_iter())
+    }
+    impl_box_push!();
+}
+
+impl<T, const N: usize> LuaPush for [T; N] where T: LuaPush {
+    fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+        push_iter(lua, self.into_iter())
+    }
+    impl_box_push!();
+}
+
 impl<K, V> LuaPush for HashMap<K, V> where K: LuaPush + Eq + Hash,
                                               V: LuaPush
 {
@@ -83,15 +89,129 @@ impl<K> LuaPush for HashSet<K> where K: LuaPush + Eq + Hash
     impl_box_push!();
 }
 
+impl<K, V> LuaPush for BTreeMap<K, V> where K: LuaPush + Ord,
+                                               V: LuaPush
+{
+    fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+        push_rec_iter(lua, self.into_iter())
+    }
+    impl_box_push!();
+}
+
+impl<K> LuaPush for BTreeSet<K> where K: LuaPush + Ord
+{
+    fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+        use std::iter;
+        push_rec_iter(lua, self.into_iter().zip(iter::repeat(true)))
+    }
+
+    impl_box_push!();
+}
+
+/// walk every key/value pair of the table at `index`, stop at the first pair
+/// that can't be converted. the key is copied before reading so that string
+/// conversions never touch the key `lua_next` relies on.
+fn read_rec_iter<K, V, F>(lua: *mut lua_State, index: i32, mut f: F) -> Option<()>
+                             where K: LuaRead, V: LuaRead, F: FnMut(K, V)
+{
+    unsafe {
+        if !sys::lua_istable(lua, index) {
+            return None;
+        }
+        let index = sys::lua_absindex(lua, index);
+        sys::lua_pushnil(lua);
+        while sys::lua_next(lua, index) != 0 {
+            sys::lua_pushvalue(lua, -2);
+            let key = K::lua_read_packed(lua, -1);
+            let value = V::lua_read_packed(lua, -2);
+            sys::lua_pop(lua, 2);
+            match (key, value) {
+                (Some(k), Some(v)) => f(k, v),
+                _ => {
+                    sys::lua_pop(lua, 1);
+                    return None;
+                }
+            }
+        }
+    }
+    Some(())
+}
+
+impl<K, V> LuaRead for HashMap<K, V> where K: LuaRead + Eq + Hash,
+                                              V: LuaRead
+{
+    fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop : i32) -> Option<HashMap<K, V>> {
+        let mut result = HashMap::new();
+        read_rec_iter(lua, index, |k, v| { result.insert(k, v); })?;
+        Some(result)
+    }
+}
+
+impl<K, V> LuaRead for BTreeMap<K, V> where K: LuaRead + Ord,
+                                               V: LuaRead
+{
+    fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop : i32) -> Option<BTreeMap<K, V>> {
+        let mut result = BTreeMap::new();
+        read_rec_iter(lua, index, |k, v| { result.insert(k, v); })?;
+        Some(result)
+    }
+}
+
+/// a set is read from the keys whose value is `true`, the same shape `LuaPush` writes
+impl<K> LuaRead for HashSet<K> where K: LuaRead + Eq + Hash
+{
+    fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop : i32) -> Option<HashSet<K>> {
+        let mut result = HashSet::new();
+        read_rec_iter(lua, index, |k, v: bool| if v { result.insert(k); })?;
+        Some(result)
+    }
+}
+
+impl<K> LuaRead for BTreeSet<K> where K: LuaRead + Ord
+{
+    fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop : i32) -> Option<BTreeSet<K>> {
+        let mut result = BTreeSet::new();
+        read_rec_iter(lua, index, |k, v: bool| if v { result.insert(k); })?;
+        Some(result)
+    }
+}
+
 impl<T> LuaRead for Vec<T> where T : LuaRead {
     fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop : i32) -> Option<Vec<T>> {
-        let mut lua_table : LuaTable = unwrap_or!(LuaRead::lua_read_at_position(lua, index), return None);
-        let mut result = vec![];
-        let len = lua_table.table_len();
-        for i in 1 .. (len + 1) {
-            let val : T = unwrap_or!(lua_table.query(i), return None);
-            result.push(val);
+        unsafe {
+            if !sys::lua_istable(lua, index) {
+                return None;
+            }
+            let index = sys::lua_absindex(lua, index);
+            let len = sys::lua_rawlen(lua, index);
+            let mut result = Vec::with_capacity(len);
+            for i in 1 .. (len + 1) {
+                sys::lua_rawgeti(lua, index, i as sys::lua_Integer);
+                let val = T::lua_read_packed(lua, -1);
+                sys::lua_pop(lua, 1);
+                result.push(unwrap_or!(val, return None));
+            }
+            Some(result)
         }
-        Some(result)
+    }
+}
+
+impl<T> LuaRead for VecDeque<T> where T : LuaRead {
+    fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, pop : i32) -> Option<VecDeque<T>> {
+        Vec::lua_read_with_pop_impl(lua, index, pop).map(VecDeque::from)
+    }
+}
+
+impl<T> LuaRead for Box<[T]> where T : LuaRead {
+    fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, pop : i32) -> Option<Box<[T]>> {
+        Vec::lua_read_with_pop_impl(lua, index, pop).map(Vec::into_boxed_slice)
+    }
+}
+
+/// the table length must match `N` exactly
+impl<T, const N: usize> LuaRead for [T; N] where T : LuaRead {
+    fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, pop : i32) -> Option<[T; N]> {
+        let val: Vec<T> = Vec::lua_read_with_pop_impl(lua, index, pop)?;
+        val.try_into().ok()
     }
 }
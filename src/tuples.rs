@@ -18,6 +18,14 @@ macro_rules! tuple_impl {
             fn lua_read_with_pop_impl(lua: *mut sys::lua_State, index: i32, _pop: i32) -> Option<($ty,)> {
                 LuaRead::lua_read_at_position(lua, index).map(|v| (v,))
             }
+
+            fn lua_read_slots() -> i32 {
+                <$ty as LuaRead>::lua_read_slots()
+            }
+
+            fn lua_read_packed(lua: *mut sys::lua_State, index: i32) -> Option<($ty,)> {
+                <$ty as LuaRead>::lua_read_packed(lua, index).map(|v| (v,))
+            }
         }
     );
 
@@ -44,9 +52,30 @@ macro_rules! tuple_impl {
             {
                 (*self).push_to_lua(lua)
             }
+
+            // one array per tuple, an element that pushes nothing leaves a hole
+            #[allow(unused_assignments)]
+            fn push_packed_to_lua(self, lua: *mut sys::lua_State) -> i32 {
+                match self {
+                    ($first, $($other),+) => {
+                        unsafe { sys::lua_newtable(lua) };
+                        let mut i = 1;
+                        if $first.push_packed_to_lua(lua) != 0 {
+                            unsafe { sys::lua_rawseti(lua, -2, i) };
+                        }
+                        $(
+                            i += 1;
+                            if $other.push_packed_to_lua(lua) != 0 {
+                                unsafe { sys::lua_rawseti(lua, -2, i) };
+                            }
+                        )+
+                        1
+                    }
+                }
+            }
         }
 
-        // TODO: what if T or U are also tuples? indices won't match
+        // nested tuples are flattened, each element advances by its own slot count
         #[allow(unused_assignments)]
         #[allow(non_snake_case)]
         impl<$first: LuaRead, $($other: LuaRead),+>
@@ -58,30 +87,43 @@ macro_rules! tuple_impl {
                     Some(v) => v,
                     None => return None
                 };
-
-                i += 1;
-                // if index > 0 {
-                //     i += 1;
-                // } else {
-                //     i -= 1;
-                // }
-                
+                i += <$first as LuaRead>::lua_read_slots();
 
                 $(
                     let $other: $other = match LuaRead::lua_read_at_position(lua, i) {
                         Some(v) => v,
                         None => return None
                     };
-                    i += 1;
-                    // if index > 0 {
-                    //     i += 1;
-                    // } else {
-                    //     i -= 1;
-                    // }
+                    i += <$other as LuaRead>::lua_read_slots();
                 )+
 
                 Some(($first, $($other),+))
+            }
 
+            fn lua_read_slots() -> i32 {
+                <$first as LuaRead>::lua_read_slots() $(+ <$other as LuaRead>::lua_read_slots())+
+            }
+
+            fn lua_read_packed(lua: *mut sys::lua_State, index: i32) -> Option<($first, $($other),+)> {
+                unsafe {
+                    if !sys::lua_istable(lua, index) {
+                        return None;
+                    }
+                    let index = sys::lua_absindex(lua, index);
+                    let mut i = 1;
+                    sys::lua_rawgeti(lua, index, i);
+                    let $first = <$first as LuaRead>::lua_read_packed(lua, -1);
+                    sys::lua_pop(lua, 1);
+                    let $first = $first?;
+                    $(
+                        i += 1;
+                        sys::lua_rawgeti(lua, index, i);
+                        let $other = <$other as LuaRead>::lua_read_packed(lua, -1);
+                        sys::lua_pop(lua, 1);
+                        let $other = $other?;
+                    )+
+                    Some(($first, $($other),+))
+                }
             }
         }
 
@@ -1,17 +1,155 @@
-use std::any::{type_name, Any};
+use std::any::{type_name, Any, TypeId};
+use std::collections::HashMap;
 use std::ffi::CString;
+use std::fmt::Display;
+use std::marker::PhantomData;
+use std::ops::{Add, Div, Mul, Neg, Rem, Sub};
 use std::mem;
 use std::ptr;
+use std::rc::Rc;
 
-use crate::object::LightObject;
+use libc::c_void;
+use log::warn;
+
+use crate::handle::Ownership;
+use crate::object::{borrow_argument, with_value, ArgBorrows, ArgFrames, LightObject};
+use crate::protocol::raise_on_error;
 use crate::{sys, LuaPush, LuaRead, LuaTable};
 
+type Upcast = Rc<dyn Fn(*mut c_void) -> *mut c_void>;
+
+/// The types used in one state and the borrows of its running calls, kept as a userdata in its
+/// registry and dropped with it.
+#[derive(Default)]
+pub(crate) struct TypeRegistry {
+    /// the `luaL_ref` of the metatable of each type
+    metatables: HashMap<TypeId, i32>,
+    /// `(derived, parent)` to the function turning a derived value into the parent it contains
+    upcasts: HashMap<(TypeId, TypeId), Upcast>,
+    pub(crate) arguments: ArgFrames,
+}
+
+/// its address is the registry key of the `TypeRegistry`
+static TYPE_REGISTRY_KEY: u8 = 0;
+
+/// Runs `f` with the `TypeRegistry` of this state, created the first time.
+///
+/// `f` only works on the registry, it must not reach the Lua state, so no other borrow of the
+/// registry can be made while it runs.
+pub(crate) fn with_type_registry<R>(lua: *mut sys::lua_State, f: impl FnOnce(&mut TypeRegistry) -> R) -> R {
+    let key = &TYPE_REGISTRY_KEY as *const u8 as *const c_void;
+    unsafe {
+        let registry = if sys::lua_rawgetp(lua, sys::LUA_REGISTRYINDEX, key) == sys::LUA_TUSERDATA {
+            sys::lua_touserdata(lua, -1) as *mut TypeRegistry
+        } else {
+            sys::lua_pop(lua, 1);
+            let raw = sys::lua_newuserdata(lua, mem::size_of::<TypeRegistry>() as libc::size_t)
+                as *mut TypeRegistry;
+            ptr::write(raw, TypeRegistry::default());
+            sys::lua_newtable(lua);
+            sys::lua_pushcfunction(lua, release_type_registry);
+            sys::lua_setfield(lua, -2, cstr!("__gc"));
+            sys::lua_setmetatable(lua, -2);
+            sys::lua_pushvalue(lua, -1);
+            sys::lua_rawsetp(lua, sys::LUA_REGISTRYINDEX, key);
+            raw
+        };
+        sys::lua_pop(lua, 1);
+        f(&mut *registry)
+    }
+}
+
+/// Empties the registry when the state is closed. Objects collected after it no longer find
+/// their metatable and are left alone.
+extern "C" fn release_type_registry(lua: *mut sys::lua_State) -> libc::c_int {
+    unsafe {
+        let registry = sys::lua_touserdata(lua, 1) as *mut TypeRegistry;
+        (*registry).arguments.forget();
+        drop(mem::take(&mut *registry));
+    }
+    0
+}
+
+/// Metatables of `Raw<T>` hold `T` values pushed as is, by `push_userdata` and
+/// `push_lightuserdata`.
+///
+/// Objects start with a `LightObject` and use the metatable of `T`, the two layouts never share
+/// a metatable so one can't be read or collected as the other.
+struct Raw<T>(PhantomData<T>);
+
+/// pushes the metatable registered for `T` (nil if there is none), returns whether it exists
+pub fn get_type_metatable<T: Any>(lua: *mut sys::lua_State) -> bool {
+    get_metatable(lua, TypeId::of::<T>())
+}
+
+fn get_metatable(lua: *mut sys::lua_State, id: TypeId) -> bool {
+    unsafe {
+        match with_type_registry(lua, |registry| registry.metatables.get(&id).copied()) {
+            Some(reference) => {
+                sys::lua_rawgeti(lua, sys::LUA_REGISTRYINDEX, reference as _) == sys::LUA_TTABLE
+            }
+            None => {
+                sys::lua_pushnil(lua);
+                false
+            }
+        }
+    }
+}
+
+/// Pushes the metatable of `T`, creating it in the registry the first time.
+///
+/// Like `luaL_newmetatable`, returns true when the table was just created.
+/// `__name` is set to the type name, it is only used in messages.
+pub fn new_type_metatable<T: Any>(lua: *mut sys::lua_State) -> bool {
+    new_metatable(lua, TypeId::of::<T>(), type_name::<T>())
+}
+
+fn new_metatable(lua: *mut sys::lua_State, id: TypeId, name: &str) -> bool {
+    unsafe {
+        if get_metatable(lua, id) {
+            return false;
+        }
+        sys::lua_pop(lua, 1);
+        sys::lua_newtable(lua);
+        sys::lua_pushlstring(lua, name.as_ptr() as *const libc::c_char, name.len());
+        sys::lua_setfield(lua, -2, cstr!("__name"));
+        sys::lua_pushvalue(lua, -1);
+        let reference = sys::luaL_ref(lua, sys::LUA_REGISTRYINDEX);
+        with_type_registry(lua, |registry| registry.metatables.insert(id, reference));
+        true
+    }
+}
+
+/// whether the value at `index` carries the metatable registered for `T`
+pub fn is_type<T: Any>(lua: *mut sys::lua_State, index: i32) -> bool {
+    has_metatable(lua, index, TypeId::of::<T>())
+}
+
+fn has_metatable(lua: *mut sys::lua_State, index: i32, id: TypeId) -> bool {
+    unsafe {
+        if sys::lua_getmetatable(lua, index) == 0 {
+            return false;
+        }
+        let same = get_metatable(lua, id) && sys::lua_rawequal(lua, -1, -2) != 0;
+        sys::lua_pop(lua, 2);
+        same
+    }
+}
+
 // Called when an object inside Lua is being dropped.
+//
+// The metatable is removed once `T` is dropped, a second call (a script calling `__gc` by hand)
+// does nothing and a resurrected object can't be read as `T` anymore.
 #[inline]
-extern "C" fn destructor_wrapper<T>(lua: *mut sys::lua_State) -> libc::c_int {
+pub(crate) extern "C" fn destructor_wrapper<T: Any>(lua: *mut sys::lua_State) -> libc::c_int {
     unsafe {
-        let obj = sys::lua_touserdata(lua, -1);
+        if sys::lua_type(lua, 1) != sys::LUA_TUSERDATA || !has_metatable(lua, 1, TypeId::of::<Raw<T>>()) {
+            return 0;
+        }
+        let obj = sys::lua_touserdata(lua, 1);
         ptr::drop_in_place(obj as *mut T);
+        sys::lua_pushnil(lua);
+        sys::lua_setmetatable(lua, 1);
         0
     }
 }
@@ -24,40 +162,38 @@ extern "C" fn destructor_wrapper<T>(lua: *mut sys::lua_State) -> libc::c_int {
 /// The way a Lua script can use the user data depends on the content of the **metatable**, which
 /// is a Lua table linked to the object.
 ///
+/// `data` is moved into the user data and dropped by its `__gc` when Lua collects it.
+///
 /// # Arguments
 ///
-///  - `metatable`: Function that fills the metatable of the object.
+///  - `metatable`: Function that fills the metatable of the object, only called the first
+///    time a `T` is pushed in this state. When `T` needs dropping, `__gc` is set after it,
+///    also on a metatable `push_lightuserdata` created first.
 ///
 pub fn push_userdata<'a, T, F>(data: T, lua: *mut sys::lua_State, mut metatable: F) -> i32
 where
     F: FnMut(LuaTable),
     T: 'a + Any,
 {
-    let lua_data_raw = unsafe { sys::lua_newuserdata(lua, mem::size_of::<T>() as libc::size_t) };
-
-    // creating a metatable
     unsafe {
-        let typeid = std::ffi::CString::new(type_name::<T>()).unwrap();
-        sys::lua_getglobal(lua, typeid.as_ptr());
-        if sys::lua_istable(lua, -1) {
-            sys::lua_setmetatable(lua, -2);
-            return 1;
-        } else {
-            sys::lua_pop(lua, 1);
-        }
-        sys::lua_newtable(lua);
-
-        let typeid = type_name::<T>();
-        // index "__typeid" corresponds to the hash of the TypeId of T
-        "__typeid".push_to_lua(lua);
-        typeid.push_to_lua(lua);
-        sys::lua_settable(lua, -3);
+        let lua_data_raw = sys::lua_newuserdata(lua, mem::size_of::<T>() as libc::size_t);
+        ptr::write(lua_data_raw as *mut T, data);
 
-        // calling the metatable closure
-        {
+        // creating a metatable
+        if new_metatable(lua, TypeId::of::<Raw<T>>(), type_name::<T>()) {
+            // calling the metatable closure
             metatable(LuaRead::lua_read(lua).unwrap());
         }
 
+        if mem::needs_drop::<T>() {
+            sys::lua_pushstring(lua, cstr!("__gc"));
+            if sys::lua_rawget(lua, -2) == sys::LUA_TNIL {
+                sys::lua_pushcfunction(lua, destructor_wrapper::<T>);
+                sys::lua_setfield(lua, -3, cstr!("__gc"));
+            }
+            sys::lua_pop(lua, 1);
+        }
+
         sys::lua_setmetatable(lua, -2);
     }
     1
@@ -90,24 +226,8 @@ where
 
     // creating a metatable
     unsafe {
-        let typeid = std::ffi::CString::new(type_name::<T>()).unwrap();
-        sys::lua_getglobal(lua, typeid.as_ptr());
-        if sys::lua_istable(lua, -1) {
-            sys::lua_setmetatable(lua, -2);
-            return 1;
-        } else {
-            sys::lua_pop(lua, 1);
-        }
-        sys::lua_newtable(lua);
-
-        let typeid = type_name::<T>();
-        // index "__typeid" corresponds to the hash of the TypeId of T
-        "__typeid".push_to_lua(lua);
-        typeid.push_to_lua(lua);
-        sys::lua_settable(lua, -3);
-
-        // calling the metatable closure
-        {
+        if new_metatable(lua, TypeId::of::<Raw<T>>(), type_name::<T>()) {
+            // calling the metatable closure
             metatable(LuaRead::lua_read(lua).unwrap());
         }
 
@@ -126,94 +246,1007 @@ where
     F: FnMut(LuaTable),
     T: 'a + Any,
 {
-    let lo = LightObject::new(data);
-    let lua_data_raw =
-        unsafe { sys::lua_newuserdata(lua, mem::size_of::<LightObject>() as libc::size_t) };
+    push_light_object::<T>(LightObject::new(data), lua)
+}
+
+/// Pushes the metatable of `T` objects, creating it with the `__gc` releasing their value.
+/// Every userdata with this metatable starts with a `LightObject`.
+fn new_object_metatable<T: Any>(lua: *mut sys::lua_State) -> bool {
+    if !new_type_metatable::<T>(lua) {
+        return false;
+    }
     unsafe {
-        ptr::write(lua_data_raw as *mut _, lo);
+        sys::lua_pushcfunction(lua, destructor_light_wrapper::<T>);
+        sys::lua_setfield(lua, -2, cstr!("__gc"));
     }
-    let typeid = type_name::<T>();
-    let cs = CString::new(typeid).unwrap();
+    true
+}
+
+/// pushes `object` as a userdata with the metatable of `T`
+pub(crate) fn push_light_object<T: Any>(object: LightObject, lua: *mut sys::lua_State) -> i32 {
     unsafe {
-        sys::lua_getglobal(lua, cs.as_ptr());
+        let raw = sys::lua_newuserdata(lua, mem::size_of::<LightObject>() as libc::size_t);
+        ptr::write(raw as *mut LightObject, object);
+        new_object_metatable::<T>(lua);
         sys::lua_setmetatable(lua, -2);
     }
     1
 }
-///
+
+/// an owned value kept right after its `LightObject`, in the same userdata
+#[repr(C)]
+struct InlineObject<T> {
+    object: LightObject,
+    value: T,
+}
+
+unsafe fn release_inline<T>(ptr: *mut c_void, drop_value: bool) {
+    if drop_value {
+        ptr::drop_in_place(ptr as *mut T);
+    }
+}
+
+/// pushes `data` moved into the userdata itself, owned by Lua like `push_userdata`
+pub(crate) fn push_inline_object<T: Any>(data: T, lua: *mut sys::lua_State) -> i32 {
+    unsafe {
+        let raw = sys::lua_newuserdata(lua, mem::size_of::<InlineObject<T>>() as libc::size_t)
+            as *mut InlineObject<T>;
+        ptr::write(ptr::addr_of_mut!((*raw).value), data);
+        let object = LightObject::from_raw::<T, T>(
+            ptr::addr_of_mut!((*raw).value) as *mut c_void,
+            Ownership::Owned,
+            release_inline::<T>,
+        );
+        ptr::write(ptr::addr_of_mut!((*raw).object), object);
+        new_object_metatable::<T>(lua);
+        sys::lua_setmetatable(lua, -2);
+    }
+    1
+}
+
+/// The `LightObject` heading the `T` object at `index`, whether or not its value was released.
+pub(crate) fn light_object<'a, T: Any>(lua: *mut sys::lua_State, index: i32) -> Option<&'a mut LightObject> {
+    unsafe {
+        if sys::lua_type(lua, index) != sys::LUA_TUSERDATA
+            || sys::lua_rawlen(lua, index) < mem::size_of::<LightObject>()
+            || !is_type::<T>(lua, index)
+        {
+            return None;
+        }
+        let obj = &mut *(sys::lua_touserdata(lua, index) as *mut LightObject);
+        if obj.type_id != TypeId::of::<T>() {
+            return None;
+        }
+        Some(obj)
+    }
+}
+
+/// The `T` pushed by `push_userdata` or `push_lightuserdata` at `index`, objects are read with
+/// `read_object` since their userdata starts with a `LightObject` rather than the `T`.
 pub fn read_userdata<'t, 'c, T>(lua: *mut sys::lua_State, index: i32) -> Option<&'t mut T>
 where
     T: 'static + Any,
 {
     unsafe {
-        let expected_typeid = type_name::<T>();
-        if sys::lua_isuserdata(lua, index) == 0 {
+        if sys::lua_isuserdata(lua, index) == 0 || !has_metatable(lua, index, TypeId::of::<Raw<T>>()) {
             return None;
         }
         let data_ptr = sys::lua_touserdata(lua, index);
         if data_ptr.is_null() {
             return None;
         }
-        if sys::lua_getmetatable(lua, index) == 0 {
+        Some(mem::transmute(data_ptr))
+    }
+}
+
+/// The object at `index` of a type inheriting `T`, with the upcast from its value to the `T`
+/// it contains. Only types registered with a parent have `__upcasts` in their metatable.
+fn derived_object<'a, T: Any>(lua: *mut sys::lua_State, index: i32) -> Option<(&'a mut LightObject, Upcast)> {
+    unsafe {
+        let top = sys::lua_gettop(lua);
+        if sys::lua_type(lua, index) != sys::LUA_TUSERDATA
+            || sys::lua_rawlen(lua, index) < mem::size_of::<LightObject>()
+            || sys::lua_getmetatable(lua, index) == 0
+        {
             return None;
         }
-
-        "__typeid".push_to_lua(lua);
-        sys::lua_gettable(lua, -2);
-        match <String as LuaRead>::lua_read(lua) {
-            Some(ref val) if val == &expected_typeid => {}
-            _ => {
-                sys::lua_pop(lua, 2);
-                return None;
-            }
+        sys::lua_pushstring(lua, cstr!("__upcasts"));
+        let inherits = sys::lua_rawget(lua, -2) == sys::LUA_TTABLE
+            && get_type_metatable::<T>(lua)
+            && sys::lua_rawget(lua, -2) != sys::LUA_TNIL;
+        sys::lua_settop(lua, top);
+        if !inherits {
+            return None;
         }
-        sys::lua_pop(lua, 2);
-        Some(mem::transmute(data_ptr))
+        let obj = &mut *(sys::lua_touserdata(lua, index) as *mut LightObject);
+        let key = (obj.type_id, TypeId::of::<T>());
+        let upcast = with_type_registry(lua, |registry| registry.upcasts.get(&key).cloned())?;
+        Some((obj, upcast))
     }
 }
 
+/// the `T` of a light object, `Shared` values are refused since they can't be mutated freely
 pub fn read_wrapper_light_userdata<'t, 'c, T>(lua: *mut sys::lua_State, index: i32) -> Option<&'t mut T>
 where
     T: 'static + Any,
 {
+    let obj = light_object::<T>(lua, index)?;
+    if obj.ptr.is_null() || obj.ownership == Ownership::Shared {
+        return None;
+    }
+    Some(unsafe { &mut *(obj.ptr as *mut T) })
+}
+
+/// moves the `T` out of an owned light object, which reads as released afterwards
+pub fn read_pop_wrapper_light_userdata<'t, 'c, T>(lua: *mut sys::lua_State, index: i32) -> Option<T>
+where
+    T: 'static + Any,
+{
+    light_object::<T>(lua, index)?.take()
+}
+
+/// Whether `name` was registered as a field of `T` in this state, fields are kept in the
+/// `__getters` table of the metatable, with `true` for a field without getter yet.
+pub fn is_field<T: Any>(lua: *mut sys::lua_State, name: &str) -> bool {
+    let name = CString::new(name).unwrap();
     unsafe {
-        let expected_typeid = type_name::<T>();
-        if sys::lua_isuserdata(lua, index) == 0 {
-            return None;
+        let top = sys::lua_gettop(lua);
+        let is_field = get_object_table::<T>(lua, cstr!("__getters"))
+            && sys::lua_getfield(lua, -1, name.as_ptr()) != sys::LUA_TNIL;
+        sys::lua_settop(lua, top);
+        is_field
+    }
+}
+
+/// Marks `name` as a field of `T` in this state, the function registered as method `name`
+/// becomes its getter.
+pub fn mark_field<T: Any>(lua: *mut sys::lua_State, name: &str) {
+    let name = CString::new(name).unwrap();
+    unsafe {
+        let top = sys::lua_gettop(lua);
+        if get_object_table::<T>(lua, cstr!("__getters")) {
+            get_object_table::<T>(lua, cstr!("__methods"));
+            if sys::lua_getfield(lua, -1, name.as_ptr()) == sys::LUA_TFUNCTION {
+                sys::lua_setfield(lua, top + 1, name.as_ptr());
+            } else if sys::lua_getfield(lua, top + 1, name.as_ptr()) == sys::LUA_TNIL {
+                sys::lua_pushboolean(lua, 1);
+                sys::lua_setfield(lua, top + 1, name.as_ptr());
+            }
         }
-        let data_ptr = sys::lua_touserdata(lua, index);
-        if data_ptr.is_null() {
-            return None;
+        sys::lua_settop(lua, top);
+    }
+}
+
+/// Pushes `table[key]` for the key at 2, falling through to the tables of the parent types.
+///
+/// The key is looked up as is with `lua_rawget`, field names are interned Lua strings so
+/// nothing is allocated or converted on the way.
+unsafe fn lookup(lua: *mut sys::lua_State, table: i32) -> libc::c_int {
+    sys::lua_pushvalue(lua, 2);
+    let t = sys::lua_rawget(lua, table);
+    if t != sys::LUA_TNIL {
+        return t;
+    }
+    sys::lua_pop(lua, 1);
+    sys::lua_pushvalue(lua, 2);
+    sys::lua_gettable(lua, table)
+}
+
+/// The `__index` of objects, upvalues are the getters and the methods of `T`.
+extern "C" fn index_metatable(lua: *mut sys::lua_State) -> libc::c_int {
+    unsafe {
+        sys::lua_settop(lua, 2);
+        if lookup(lua, sys::lua_upvalueindex(1)) == sys::LUA_TFUNCTION {
+            sys::lua_pushvalue(lua, 1);
+            sys::lua_call(lua, 1, 1);
+            return 1;
         }
-        let obj: &mut LightObject = mem::transmute(data_ptr);
-        if obj.name != expected_typeid {
-            return None;
+        sys::lua_pop(lua, 1);
+        lookup(lua, sys::lua_upvalueindex(2));
+        1
+    }
+}
+
+/// The `__newindex` of objects, upvalues are the setters and the getters of `T`.
+extern "C" fn newindex_metatable(lua: *mut sys::lua_State) -> libc::c_int {
+    unsafe {
+        sys::lua_settop(lua, 3);
+        if lookup(lua, sys::lua_upvalueindex(1)) == sys::LUA_TFUNCTION {
+            sys::lua_pushvalue(lua, 1);
+            sys::lua_pushvalue(lua, 3);
+            sys::lua_call(lua, 2, 0);
+            return 0;
         }
-        Some(mem::transmute(obj.ptr))
+        let is_field = lookup(lua, sys::lua_upvalueindex(2)) != sys::LUA_TNIL;
+        let msg = {
+            let key = String::lua_read_at_position(lua, 2).unwrap_or_default();
+            if is_field {
+                format!("field {key} is read only")
+            } else {
+                format!("key {key} not a field")
+            }
+        };
+        raise_on_error(lua, Err::<libc::c_int, _>(msg))
     }
 }
 
+/// Pushes the metatable of `T` set up for objects: `__index` reading the `__getters` of the
+/// fields then the `__methods`, `__newindex` writing through the `__setters`, `__statics` for
+/// the functions of the class table and `__gc` releasing the value.
+///
+/// Returns true when it was just set up, `LuaObject` and `UserData` share this layout.
+pub(crate) fn ensure_object_metatable<T: Any>(lua: *mut sys::lua_State) -> bool {
+    unsafe {
+        // the metatable may already exist without methods when a value was pushed first
+        new_object_metatable::<T>(lua);
+        sys::lua_pushstring(lua, cstr!("__methods"));
+        if sys::lua_rawget(lua, -2) == sys::LUA_TTABLE {
+            sys::lua_pop(lua, 1);
+            return false;
+        }
+        sys::lua_pop(lua, 1);
+        let metatable = sys::lua_gettop(lua);
 
-pub fn read_pop_wrapper_light_userdata<'t, 'c, T>(lua: *mut sys::lua_State, index: i32) -> Option<T>
+        sys::lua_newtable(lua);
+        sys::lua_newtable(lua);
+        sys::lua_newtable(lua);
+        let (methods, getters, setters) = (metatable + 1, metatable + 2, metatable + 3);
+        sys::lua_pushvalue(lua, getters);
+        sys::lua_pushvalue(lua, methods);
+        sys::lua_pushcclosure(lua, index_metatable, 2);
+        sys::lua_setfield(lua, metatable, cstr!("__index"));
+        sys::lua_pushvalue(lua, setters);
+        sys::lua_pushvalue(lua, getters);
+        sys::lua_pushcclosure(lua, newindex_metatable, 2);
+        sys::lua_setfield(lua, metatable, cstr!("__newindex"));
+        sys::lua_setfield(lua, metatable, cstr!("__setters"));
+        sys::lua_setfield(lua, metatable, cstr!("__getters"));
+        sys::lua_setfield(lua, metatable, cstr!("__methods"));
+
+        sys::lua_newtable(lua);
+        sys::lua_setfield(lua, metatable, cstr!("__statics"));
+        true
+    }
+}
+
+/// Pushes the field `name` of the metatable of `T` (`__methods`, `__getters`, `__setters`
+/// or `__statics`), returns false and pushes nil when `T` isn't registered.
+pub(crate) fn get_object_table<T: Any>(lua: *mut sys::lua_State, name: *const libc::c_char) -> bool {
+    unsafe {
+        if !get_type_metatable::<T>(lua) {
+            return false;
+        }
+        sys::lua_pushstring(lua, name);
+        let t = sys::lua_rawget(lua, -2);
+        sys::lua_remove(lua, -2);
+        t == sys::LUA_TTABLE
+    }
+}
+
+/// releases the value of a `LightObject`, the `del` of light classes
+pub extern "C" fn delete_light_object<T: Any>(lua: *mut sys::lua_State) -> libc::c_int {
+    if let Some(obj) = light_object::<T>(lua, 1) {
+        obj.release();
+    }
+    0
+}
+
+/// the `__gc` of light objects, releases the value unless `del` already did
+pub(crate) extern "C" fn destructor_light_wrapper<T: Any>(lua: *mut sys::lua_State) -> libc::c_int {
+    delete_light_object::<T>(lua);
+    unsafe {
+        sys::lua_pushnil(lua);
+        sys::lua_setmetatable(lua, 1);
+    }
+    0
+}
+
+/// `obj:is_a(Class)`, whether the object is of the class or inherits it, the class is given
+/// by its table or by the name it was registered with
+pub extern "C" fn is_a(lua: *mut sys::lua_State) -> libc::c_int {
+    let is_a = unsafe {
+        sys::lua_settop(lua, 2);
+        sys::lua_getmetatable(lua, 1) != 0
+            && (is_class(lua, 3) || {
+                sys::lua_pushstring(lua, cstr!("__upcasts"));
+                sys::lua_rawget(lua, 3) == sys::LUA_TTABLE && any_class(lua, 4)
+            })
+    };
+    unsafe { sys::lua_pushboolean(lua, is_a as libc::c_int) };
+    1
+}
+
+/// whether `metatable` belongs to the class at 2
+unsafe fn is_class(lua: *mut sys::lua_State, metatable: i32) -> bool {
+    let metatable = sys::lua_absindex(lua, metatable);
+    if sys::lua_type(lua, 2) == sys::LUA_TSTRING {
+        sys::lua_pushstring(lua, cstr!("__class"));
+    } else {
+        sys::lua_pushstring(lua, cstr!("__statics"));
+    }
+    sys::lua_rawget(lua, metatable);
+    let same = sys::lua_rawequal(lua, -1, 2) != 0;
+    sys::lua_pop(lua, 1);
+    same
+}
+
+unsafe fn any_class(lua: *mut sys::lua_State, metatables: i32) -> bool {
+    sys::lua_pushnil(lua);
+    while sys::lua_next(lua, metatables) != 0 {
+        if is_class(lua, -1) {
+            sys::lua_pop(lua, 2);
+            return true;
+        }
+        sys::lua_pop(lua, 1);
+    }
+    false
+}
+
+/// A Rust type scripts can use as an object.
+///
+/// Fields, methods and metamethods are declared in one place and registered the first time a
+/// value of the type is pushed (or by `Lua::register_userdata`, which also names the class table
+/// holding the functions):
+///
+/// ```ignore
+/// impl UserData for Vec2 {
+///     fn add_fields(fields: &mut UserDataFields<Self>) {
+///         fields.add_field_get("x", |v| v.x);
+///         fields.add_field_set("x", |v, x: f64| v.x = x);
+///     }
+///     fn add_methods(methods: &mut UserDataMethods<Self>) {
+///         methods.add_method("len", |v, ()| (v.x * v.x + v.y * v.y).sqrt());
+///         methods.add_method_mut("scale", |v, k: f64| { v.x *= k; v.y *= k; });
+///         methods.add_function("new", |(x, y): (f64, f64)| Vec2 { x, y });
+///     }
+/// }
+/// ```
+///
+/// Values pushed as is are owned by Lua and dropped by its GC, see `Owned`, `Shared` and
+/// `Borrowed` for the other ownerships. `LIGHT` classes get a `del` releasing objects early.
+/// `&T` and `&mut T` are read from Lua as is, `impl_userdata_push!(T)` lets `T` be pushed.
+pub trait UserData: Any + Sized {
+    const LIGHT: bool = false;
+
+    fn add_fields(_fields: &mut UserDataFields<Self>) {}
+
+    fn add_methods(_methods: &mut UserDataMethods<Self>) {}
+
+    fn add_meta_methods(_methods: &mut UserDataMetaMethods<Self>) {}
+}
+
+/// a Rust callback reading its arguments from the stack, an `Err` is raised as a Lua error
+type Callback = Box<dyn FnMut(*mut sys::lua_State) -> Result<i32, String>>;
+
+extern "C" fn call_callback(lua: *mut sys::lua_State) -> libc::c_int {
+    let ret = unsafe {
+        let _borrows = ArgBorrows::open(lua);
+        let callback = sys::lua_touserdata(lua, sys::lua_upvalueindex(1)) as *mut Callback;
+        (*callback)(lua)
+    };
+    raise_on_error(lua, ret)
+}
+
+fn push_callback(lua: *mut sys::lua_State, callback: Callback) {
+    push_userdata(callback, lua, |_| {});
+    unsafe { sys::lua_pushcclosure(lua, call_callback, 1) };
+}
+
+/// sets the value on the top of the stack as `table[name]`
+fn set_table_field(lua: *mut sys::lua_State, table: i32, name: &str) {
+    let name = CString::new(name).unwrap();
+    unsafe { sys::lua_setfield(lua, table, name.as_ptr()) };
+}
+
+/// The `T` object at `index` to mutate, `None` when it was released or is `Shared`.
+/// Objects of types inheriting `T` give the `T` they contain.
+pub fn read_object<'a, T: UserData>(lua: *mut sys::lua_State, index: i32) -> Option<&'a mut T> {
+    if let Some(obj) = read_wrapper_light_userdata(lua, index) {
+        return Some(obj);
+    }
+    let (obj, upcast) = derived_object::<T>(lua, index)?;
+    if obj.ptr.is_null() || obj.ownership == Ownership::Shared {
+        return None;
+    }
+    Some(unsafe { &mut *(upcast(obj.ptr) as *mut T) })
+}
+
+/// the `LightObject` of the `T` object at `index`, also for the types inheriting `T`
+fn object_header<'a, T: Any>(lua: *mut sys::lua_State, index: i32) -> Option<&'a mut LightObject> {
+    light_object::<T>(lua, index).or_else(|| derived_object::<T>(lua, index).map(|(obj, _)| obj))
+}
+
+/// The `T` object at `index` whatever its ownership, `None` when it was released.
+/// Values behind a lock are only reached by their fields and methods.
+pub fn read_object_ref<'a, T: UserData>(lua: *mut sys::lua_State, index: i32) -> Option<&'a T> {
+    let (obj, upcast) = match light_object::<T>(lua, index) {
+        Some(obj) => (obj, None),
+        None => derived_object::<T>(lua, index).map(|(obj, upcast)| (obj, Some(upcast)))?,
+    };
+    if obj.ptr.is_null() || obj.is_locked() {
+        return None;
+    }
+    let ptr = upcast.map_or(obj.ptr, |upcast| upcast(obj.ptr));
+    Some(unsafe { &*(ptr as *const T) })
+}
+
+/// Runs `f` with the object at 1, a `Lua` error when it isn't a usable `T`.
+///
+/// `f` runs while the value is borrowed, possibly under its lock, so it only computes a Rust
+/// value: pushing it and raising errors are left to the caller once the borrow is released.
+fn with_self<T, R, F>(lua: *mut sys::lua_State, name: &str, f: F) -> Result<R, String>
 where
-    T: 'static + Any,
+    T: UserData,
+    F: FnOnce(&T) -> R,
 {
-    unsafe {
-        let expected_typeid = type_name::<T>();
-        if sys::lua_isuserdata(lua, index) == 0 {
-            return None;
+    let (mut f, mut ret) = (Some(f), None);
+    call_self::<T>(lua, name, false, &mut |ptr| {
+        ret = f.take().map(|f| f(unsafe { &*(ptr as *const T) }))
+    })?;
+    Ok(ret.unwrap())
+}
+
+fn with_self_mut<T, R, F>(lua: *mut sys::lua_State, name: &str, f: F) -> Result<R, String>
+where
+    T: UserData,
+    F: FnOnce(&mut T) -> R,
+{
+    let (mut f, mut ret) = (Some(f), None);
+    call_self::<T>(lua, name, true, &mut |ptr| {
+        ret = f.take().map(|f| f(unsafe { &mut *(ptr as *mut T) }))
+    })?;
+    Ok(ret.unwrap())
+}
+
+fn call_self<T: UserData>(
+    lua: *mut sys::lua_State,
+    name: &str,
+    mutable: bool,
+    f: &mut dyn FnMut(*mut c_void),
+) -> Result<(), String> {
+    let (obj, upcast): (*mut LightObject, _) = match light_object::<T>(lua, 1) {
+        Some(obj) => (obj, None),
+        None => derived_object::<T>(lua, 1)
+            .map(|(obj, upcast)| (obj as *mut LightObject, Some(upcast)))
+            .ok_or_else(|| format!("bad self for '{}', {} expected", name, type_name::<T>()))?,
+    };
+    let f: &mut dyn FnMut(*mut c_void) = match &upcast {
+        Some(upcast) => &mut |ptr| f(upcast(ptr)),
+        None => f,
+    };
+    let reason = unsafe {
+        if (*obj).ptr.is_null() {
+            "was released"
+        } else if mutable && (*obj).ownership == Ownership::Shared && !(*obj).is_locked() {
+            "is shared and read only"
+        } else {
+            match with_value(obj, mutable, f) {
+                Ok(()) => return Ok(()),
+                Err(reason) => reason,
+            }
         }
-        let data_ptr = sys::lua_touserdata(lua, index);
-        if data_ptr.is_null() {
-            return None;
+    };
+    Err(format!("bad self for '{}', {} {}", name, type_name::<T>(), reason))
+}
+
+fn check_args<A: LuaRead>(lua: *mut sys::lua_State, index: i32, name: &str) -> Result<A, String> {
+    LuaRead::lua_read_at_position(lua, index)
+        .ok_or_else(|| format!("wrong parameter types for '{}'", name))
+}
+
+/// Registers fields of `T`, read as `obj.name` and written as `obj.name = v`.
+pub struct UserDataFields<T> {
+    lua: *mut sys::lua_State,
+    getters: i32,
+    setters: i32,
+    marker: PhantomData<T>,
+}
+
+impl<T: UserData> UserDataFields<T> {
+    /// a field read by `get`
+    pub fn add_field_get<R, F>(&mut self, name: &'static str, mut get: F) -> &mut Self
+    where
+        F: FnMut(&T) -> R + 'static,
+        R: LuaPush,
+    {
+        push_callback(
+            self.lua,
+            Box::new(move |lua| with_self::<T, _, _>(lua, name, &mut get)?.try_push_to_lua(lua)),
+        );
+        set_table_field(self.lua, self.getters, name);
+        self
+    }
+
+    /// A field read by reference, for values that only push as `&V`.
+    ///
+    /// The reference is pushed while the value is borrowed, `&V` must only push plain values.
+    pub fn add_field_ref<V>(&mut self, name: &'static str, get: for<'b> fn(&'b T) -> &'b V) -> &mut Self
+    where
+        V: 'static,
+        for<'b> &'b V: LuaPush,
+    {
+        push_callback(
+            self.lua,
+            Box::new(move |lua| with_self::<T, _, _>(lua, name, |obj| get(obj).try_push_to_lua(lua))?),
+        );
+        set_table_field(self.lua, self.getters, name);
+        self
+    }
+
+    /// a field written by `set`, assigning a value that can't be read as `A` is a Lua error
+    pub fn add_field_set<A, F>(&mut self, name: &'static str, mut set: F) -> &mut Self
+    where
+        F: FnMut(&mut T, A) + 'static,
+        A: LuaRead,
+    {
+        push_callback(
+            self.lua,
+            Box::new(move |lua| {
+                let value = check_args(lua, 2, name)?;
+                with_self_mut::<T, _, _>(lua, name, |obj| set(obj, value))?;
+                Ok(0)
+            }),
+        );
+        set_table_field(self.lua, self.setters, name);
+        self
+    }
+}
+
+/// Registers methods of `T` and the functions of its class table.
+///
+/// The arguments after `self` are read as `A`, a tuple for several of them and `()` for none.
+pub struct UserDataMethods<T> {
+    lua: *mut sys::lua_State,
+    metatable: i32,
+    methods: i32,
+    statics: i32,
+    marker: PhantomData<T>,
+}
+
+impl<T: UserData> UserDataMethods<T> {
+    /// `obj:name(...)` borrowing the object
+    pub fn add_method<A, R, F>(&mut self, name: &str, mut method: F) -> &mut Self
+    where
+        F: FnMut(&T, A) -> R + 'static,
+        A: LuaRead,
+        R: LuaPush,
+    {
+        let fname = name.to_string();
+        push_callback(
+            self.lua,
+            Box::new(move |lua| {
+                let args = check_args(lua, 2, &fname)?;
+                with_self::<T, _, _>(lua, &fname, |obj| method(obj, args))?.try_push_to_lua(lua)
+            }),
+        );
+        set_table_field(self.lua, self.methods, name);
+        self
+    }
+
+    /// `obj:name(...)` mutating the object
+    pub fn add_method_mut<A, R, F>(&mut self, name: &str, mut method: F) -> &mut Self
+    where
+        F: FnMut(&mut T, A) -> R + 'static,
+        A: LuaRead,
+        R: LuaPush,
+    {
+        let fname = name.to_string();
+        push_callback(
+            self.lua,
+            Box::new(move |lua| {
+                let args = check_args(lua, 2, &fname)?;
+                with_self_mut::<T, _, _>(lua, &fname, |obj| method(obj, args))?.try_push_to_lua(lua)
+            }),
+        );
+        set_table_field(self.lua, self.methods, name);
+        self
+    }
+
+    /// a function without `self`, set on the class table and reachable from objects
+    pub fn add_function<A, R, F>(&mut self, name: &str, mut function: F) -> &mut Self
+    where
+        F: FnMut(A) -> R + 'static,
+        A: LuaRead,
+        R: LuaPush,
+    {
+        let fname = name.to_string();
+        push_callback(
+            self.lua,
+            Box::new(move |lua| {
+                let args = check_args(lua, 1, &fname)?;
+                function(args).try_push_to_lua(lua)
+            }),
+        );
+        self.set_function(name);
+        self
+    }
+
+    /// a raw C function, set on the class table and reachable from objects
+    pub fn register(
+        &mut self,
+        name: &str,
+        func: extern "C" fn(*mut sys::lua_State) -> libc::c_int,
+    ) -> &mut Self {
+        unsafe { sys::lua_pushcfunction(self.lua, func) };
+        self.set_function(name);
+        self
+    }
+
+    /// Makes `T` a kind of `P`: the fields, methods and class functions of `P` that `T` doesn't
+    /// define are reached from `T`, the `P` methods get the parent `upcast` takes out of the object.
+    ///
+    /// A type has one parent, which can have its own. Rust functions reading `&P` or `&mut P`
+    /// accept `T` objects too.
+    pub fn inherit<P: UserData>(&mut self, parent: fn(&mut T) -> &mut P) -> &mut Self {
+        let lua = self.lua;
+        unsafe {
+            if sys::lua_getmetatable(lua, self.methods) != 0 {
+                sys::lua_pop(lua, 1);
+                warn!("{} already has a parent, {} is ignored", type_name::<T>(), type_name::<P>());
+                return self;
+            }
+            register_userdata::<P>(lua);
+            let top = sys::lua_gettop(lua);
+            get_type_metatable::<P>(lua);
+            let metatable = top + 1;
+            for name in [cstr!("__methods"), cstr!("__getters"), cstr!("__setters"), cstr!("__statics")] {
+                sys::lua_getfield(lua, self.metatable, name);
+                sys::lua_newtable(lua);
+                sys::lua_getfield(lua, metatable, name);
+                sys::lua_setfield(lua, -2, cstr!("__index"));
+                sys::lua_setmetatable(lua, -2);
+                sys::lua_pop(lua, 1);
+            }
+
+            // the metatables of all the ancestors, keyed by themselves
+            sys::lua_newtable(lua);
+            sys::lua_pushvalue(lua, metatable);
+            sys::lua_pushvalue(lua, metatable);
+            sys::lua_rawset(lua, top + 2);
+            sys::lua_getfield(lua, metatable, cstr!("__upcasts"));
+            if sys::lua_type(lua, -1) == sys::LUA_TTABLE {
+                sys::lua_pushnil(lua);
+                while sys::lua_next(lua, -2) != 0 {
+                    sys::lua_pushvalue(lua, -2);
+                    sys::lua_insert(lua, -2);
+                    sys::lua_rawset(lua, top + 2);
+                }
+            }
+            sys::lua_pop(lua, 1);
+            sys::lua_setfield(lua, self.metatable, cstr!("__upcasts"));
+            sys::lua_settop(lua, top);
         }
-        let obj: &mut LightObject = mem::transmute(data_ptr);
-        if obj.name != expected_typeid || obj.ptr.is_null() {
-            return None;
+
+        let upcast: Upcast =
+            Rc::new(move |ptr| parent(unsafe { &mut *(ptr as *mut T) }) as *mut P as *mut c_void);
+        with_type_registry(lua, |registry| {
+            let upcasts = &mut registry.upcasts;
+            let ancestors: Vec<_> = upcasts
+                .iter()
+                .filter(|((derived, _), _)| *derived == TypeId::of::<P>())
+                .map(|((_, ancestor), f)| (*ancestor, f.clone()))
+                .collect();
+            for (ancestor, f) in ancestors {
+                let upcast = upcast.clone();
+                upcasts.insert((TypeId::of::<T>(), ancestor), Rc::new(move |ptr| f(upcast(ptr))));
+            }
+            upcasts.insert((TypeId::of::<T>(), TypeId::of::<P>()), upcast);
+        });
+        self
+    }
+
+    fn set_function(&mut self, name: &str) {
+        unsafe { sys::lua_pushvalue(self.lua, -1) };
+        set_table_field(self.lua, self.statics, name);
+        set_table_field(self.lua, self.methods, name);
+    }
+}
+
+/// The metamethods scripts can trigger on objects.
+///
+/// `IDiv` and the bitwise operators need Lua 5.3, `Close` 5.4 and `Pairs` 5.2 or later,
+/// older versions ignore them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MetaMethod {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Unm,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    BNot,
+    Shl,
+    Shr,
+    Eq,
+    Lt,
+    Le,
+    Concat,
+    Len,
+    Call,
+    ToString,
+    Pairs,
+    Close,
+}
+
+impl MetaMethod {
+    pub fn name(self) -> &'static str {
+        match self {
+            MetaMethod::Add => "__add",
+            MetaMethod::Sub => "__sub",
+            MetaMethod::Mul => "__mul",
+            MetaMethod::Div => "__div",
+            MetaMethod::Mod => "__mod",
+            MetaMethod::Pow => "__pow",
+            MetaMethod::Unm => "__unm",
+            MetaMethod::IDiv => "__idiv",
+            MetaMethod::BAnd => "__band",
+            MetaMethod::BOr => "__bor",
+            MetaMethod::BXor => "__bxor",
+            MetaMethod::BNot => "__bnot",
+            MetaMethod::Shl => "__shl",
+            MetaMethod::Shr => "__shr",
+            MetaMethod::Eq => "__eq",
+            MetaMethod::Lt => "__lt",
+            MetaMethod::Le => "__le",
+            MetaMethod::Concat => "__concat",
+            MetaMethod::Len => "__len",
+            MetaMethod::Call => "__call",
+            MetaMethod::ToString => "__tostring",
+            MetaMethod::Pairs => "__pairs",
+            MetaMethod::Close => "__close",
+        }
+    }
+}
+
+impl AsRef<str> for MetaMethod {
+    fn as_ref(&self) -> &str {
+        self.name()
+    }
+}
+
+/// `__index`, `__newindex` and `__gc` belong to the field and method dispatch
+pub(crate) fn is_reserved_meta(name: &str) -> bool {
+    matches!(
+        name,
+        "__index"
+            | "__newindex"
+            | "__gc"
+            | "__methods"
+            | "__getters"
+            | "__setters"
+            | "__statics"
+            | "__upcasts"
+            | "__class"
+    )
+}
+
+/// Registers metamethods of `T`, named by a `MetaMethod` or a string such as `"__tostring"`.
+///
+/// `__index`, `__newindex` and `__gc` belong to the field and method dispatch and are refused.
+/// The `derive_*` methods map the std traits implemented by `T` to their operators.
+pub struct UserDataMetaMethods<T> {
+    lua: *mut sys::lua_State,
+    metatable: i32,
+    marker: PhantomData<T>,
+}
+
+impl<T: UserData> UserDataMetaMethods<T> {
+    /// a metamethod called with the object first, such as `__tostring`, `__len` or `__call`
+    pub fn add_meta_method<N, A, R, F>(&mut self, name: N, mut method: F) -> &mut Self
+    where
+        N: AsRef<str>,
+        F: FnMut(&T, A) -> R + 'static,
+        A: LuaRead,
+        R: LuaPush,
+    {
+        let fname = name.as_ref().to_string();
+        self.set(
+            name.as_ref(),
+            Box::new(move |lua| {
+                let args = check_args(lua, 2, &fname)?;
+                with_self::<T, _, _>(lua, &fname, |obj| method(obj, args))?.try_push_to_lua(lua)
+            }),
+        )
+    }
+
+    pub fn add_meta_method_mut<N, A, R, F>(&mut self, name: N, mut method: F) -> &mut Self
+    where
+        N: AsRef<str>,
+        F: FnMut(&mut T, A) -> R + 'static,
+        A: LuaRead,
+        R: LuaPush,
+    {
+        let fname = name.as_ref().to_string();
+        self.set(
+            name.as_ref(),
+            Box::new(move |lua| {
+                let args = check_args(lua, 2, &fname)?;
+                with_self_mut::<T, _, _>(lua, &fname, |obj| method(obj, args))?.try_push_to_lua(lua)
+            }),
+        )
+    }
+
+    /// a metamethod reading all its operands as `A`, for operators where `self` may be second
+    pub fn add_meta_function<N, A, R, F>(&mut self, name: N, mut function: F) -> &mut Self
+    where
+        N: AsRef<str>,
+        F: FnMut(A) -> R + 'static,
+        A: LuaRead,
+        R: LuaPush,
+    {
+        let fname = name.as_ref().to_string();
+        self.set(
+            name.as_ref(),
+            Box::new(move |lua| {
+                let args = check_args(lua, 1, &fname)?;
+                function(args).try_push_to_lua(lua)
+            }),
+        )
+    }
+
+    /// `__tostring` from `Display`
+    pub fn derive_display(&mut self) -> &mut Self
+    where
+        T: Display,
+    {
+        self.add_meta_method(MetaMethod::ToString, |obj, ()| obj.to_string())
+    }
+
+    /// `__eq` from `PartialEq`, an object is never equal to a value of another type
+    pub fn derive_eq(&mut self) -> &mut Self
+    where
+        T: PartialEq,
+    {
+        self.add_meta_function(MetaMethod::Eq, |(a, b): (Option<&T>, Option<&T>)| {
+            matches!((a, b), (Some(a), Some(b)) if a == b)
+        })
+    }
+
+    /// `__lt` and `__le` from `PartialOrd`, comparing with another type is a Lua error
+    pub fn derive_ord(&mut self) -> &mut Self
+    where
+        T: PartialOrd,
+    {
+        self.add_meta_function(MetaMethod::Lt, |(a, b): (&T, &T)| a < b);
+        self.add_meta_function(MetaMethod::Le, |(a, b): (&T, &T)| a <= b)
+    }
+
+    /// `__add` from `Add`, both operands must be objects of type `T`
+    pub fn derive_add(&mut self) -> &mut Self
+    where
+        T: Add<Output = T> + Clone,
+    {
+        self.binary_op(MetaMethod::Add, |a, b| a + b)
+    }
+
+    pub fn derive_sub(&mut self) -> &mut Self
+    where
+        T: Sub<Output = T> + Clone,
+    {
+        self.binary_op(MetaMethod::Sub, |a, b| a - b)
+    }
+
+    pub fn derive_mul(&mut self) -> &mut Self
+    where
+        T: Mul<Output = T> + Clone,
+    {
+        self.binary_op(MetaMethod::Mul, |a, b| a * b)
+    }
+
+    pub fn derive_div(&mut self) -> &mut Self
+    where
+        T: Div<Output = T> + Clone,
+    {
+        self.binary_op(MetaMethod::Div, |a, b| a / b)
+    }
+
+    pub fn derive_rem(&mut self) -> &mut Self
+    where
+        T: Rem<Output = T> + Clone,
+    {
+        self.binary_op(MetaMethod::Mod, |a, b| a % b)
+    }
+
+    /// `__unm` from `Neg`
+    pub fn derive_neg(&mut self) -> &mut Self
+    where
+        T: Neg<Output = T> + Clone,
+    {
+        self.set(
+            MetaMethod::Unm.name(),
+            Box::new(|lua| Ok(push_object(with_self::<T, _, _>(lua, "__unm", |obj| -obj.clone())?, lua))),
+        )
+    }
+
+    fn binary_op(&mut self, meta: MetaMethod, op: fn(T, T) -> T) -> &mut Self
+    where
+        T: Clone,
+    {
+        self.set(
+            meta.name(),
+            Box::new(move |lua| {
+                let (a, b): (&T, &T) = check_args(lua, 1, meta.name())?;
+                Ok(push_object(op(a.clone(), b.clone()), lua))
+            }),
+        )
+    }
+
+    fn set(&mut self, name: &str, callback: Callback) -> &mut Self {
+        if is_reserved_meta(name) {
+            warn!("metamethod {} of {} is kept by hclua", name, type_name::<T>());
+            return self;
+        }
+        push_callback(self.lua, callback);
+        set_table_field(self.lua, self.metatable, name);
+        self
+    }
+}
+
+/// Registers `T` in this state the first time, running its `UserData` declarations.
+/// Returns false when it was already registered.
+pub fn register_userdata<T: UserData>(lua: *mut sys::lua_State) -> bool {
+    unsafe {
+        let top = sys::lua_gettop(lua);
+        if !ensure_object_metatable::<T>(lua) {
+            sys::lua_settop(lua, top);
+            return false;
+        }
+        let metatable = sys::lua_gettop(lua);
+        sys::lua_getfield(lua, metatable, cstr!("__methods"));
+        sys::lua_getfield(lua, metatable, cstr!("__statics"));
+        sys::lua_getfield(lua, metatable, cstr!("__getters"));
+        sys::lua_getfield(lua, metatable, cstr!("__setters"));
+        T::add_fields(&mut UserDataFields {
+            lua,
+            getters: metatable + 3,
+            setters: metatable + 4,
+            marker: PhantomData,
+        });
+        let mut methods = UserDataMethods {
+            lua,
+            metatable,
+            methods: metatable + 1,
+            statics: metatable + 2,
+            marker: PhantomData,
+        };
+        methods.register("is_a", is_a);
+        if T::LIGHT {
+            methods.register("del", delete_light_object::<T>);
         }
-        let val: &mut T = mem::transmute(obj.ptr);
-        let val = Box::from_raw(val);
-        obj.ptr = ptr::null_mut() as *mut libc::c_void;
-        Some(*val)
+        T::add_methods(&mut methods);
+        T::add_meta_methods(&mut UserDataMetaMethods {
+            lua,
+            metatable,
+            marker: PhantomData,
+        });
+        sys::lua_settop(lua, top);
+    }
+    true
+}
+
+/// pushes `value` as a `T` object owned by Lua, registering `T` first when needed
+pub fn push_object<T: UserData>(value: T, lua: *mut sys::lua_State) -> i32 {
+    register_userdata::<T>(lua);
+    push_inline_object(value, lua)
+}
+
+/// An argument of a call from Lua borrows the object until the call ends, a second `&mut T` of
+/// the same object in that call reads as `None`.
+impl<'a, T: UserData> LuaRead for &'a mut T {
+    fn lua_read_with_pop_impl(lua: *mut sys::lua_State, index: i32, _pop: i32) -> Option<&'a mut T> {
+        let value = read_object(lua, index)?;
+        borrow_argument(lua, object_header::<T>(lua, index)?, true).then_some(value)
+    }
+}
+
+impl<'a, T: UserData> LuaRead for &'a T {
+    fn lua_read_with_pop_impl(lua: *mut sys::lua_State, index: i32, _pop: i32) -> Option<&'a T> {
+        let value = read_object_ref(lua, index)?;
+        borrow_argument(lua, object_header::<T>(lua, index)?, false).then_some(value)
     }
 }
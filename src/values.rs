@@ -1,22 +1,82 @@
 use crate::{
-    impl_box_push, lua_State, lua_pushnil, sys, LuaPush, LuaRead, LuaWrapperValue, ProtoLua,
-    WrapSerde,
+    impl_box_push, lua_State, lua_pushnil, sys, LuaDeserializer, LuaGuard, LuaPush, LuaRead,
+    LuaSerializer, WrapSerde,
 };
-use hcproto::Value;
 use libc;
+use log::warn;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, ffi::CString, fmt::Debug, net::SocketAddr, ptr};
+use std::num::{
+    NonZeroI128, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI8, NonZeroIsize, NonZeroU128,
+    NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU8, NonZeroUsize, Saturating, Wrapping,
+};
+use std::{borrow::Cow, ffi::CString, fmt::Debug, net::SocketAddr, ops::Deref};
 
+/// Bytes pushed and read as a Lua string byte for byte.
+///
+/// A plain `Vec<u8>` is a sequence like any other `Vec` and maps to a table of integers,
+/// wrap it in `RawString` to keep binary data as one exact Lua string instead.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
 pub struct RawString(pub Vec<u8>);
 
 pub struct WrapperObject<T>(pub T);
 
+/// Reads the inner value without implicit coercion.
+///
+/// The plain reads follow Lua's own conversions: a number is accepted where a string is
+/// expected and a numeric string where a number is expected. Wrapped in `Strict` a value
+/// is only read from the matching Lua type, an integer additionally has to be a Lua integer
+/// (on 5.1/5.2 a number with an integral value), so `"12"` is refused for `Strict<i64>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Strict<T>(pub T);
+
+impl<T: LuaPush> LuaPush for Strict<T> {
+    fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+        self.0.push_to_lua(lua)
+    }
+    impl_box_push!();
+}
+
+/// Reads the number at `index` as an integer without losing information.
+///
+/// Lua integers are taken as is, floats are only accepted when they hold an exact
+/// integer value, so `3.0` reads as `3` while `3.5` or `inf` read as `None`.
+pub(crate) fn read_integer(lua: *mut lua_State, index: i32) -> Option<i128> {
+    unsafe {
+        if sys::lua_isinteger(lua, index) != 0 {
+            return Some(sys::lua_tointeger(lua, index) as i128);
+        }
+        let mut success = 0;
+        let val = sys::lua_tonumberx(lua, index, &mut success);
+        // 2^127, the first float that no longer fits in an i128
+        const LIMIT: f64 = 170141183460469231731687303715884105728.0;
+        if success == 0 || val.fract() != 0.0 || !(-LIMIT..LIMIT).contains(&val) {
+            return None;
+        }
+        Some(val as i128)
+    }
+}
+
 macro_rules! integer_impl(
     ($t:ident) => (
         impl LuaPush for $t {
+            /// too large for a lua integer, a float would lose the low digits so the value is
+            /// refused like `LuaSerializer` does, nil is pushed in its place
             fn push_to_lua(self, lua: *mut lua_State) -> i32 {
-                unsafe { sys::lua_pushinteger(lua, self as sys::lua_Integer) };
-                1
+                match self.try_push_to_lua(lua) {
+                    Ok(size) => size,
+                    Err(e) => {
+                        warn!("{}", e);
+                        unsafe { sys::lua_pushnil(lua) };
+                        1
+                    }
+                }
+            }
+
+            fn try_push_to_lua(self, lua: *mut lua_State) -> Result<i32, String> {
+                let v = sys::lua_Integer::try_from(self)
+                    .map_err(|_| format!("integer {} out of range of a Lua integer", self))?;
+                unsafe { sys::lua_pushinteger(lua, v) };
+                Ok(1)
             }
             fn box_push_to_lua(self: Box<Self>, lua: *mut lua_State) -> i32
             {
@@ -26,8 +86,7 @@ macro_rules! integer_impl(
 
         impl LuaPush for &$t {
             fn push_to_lua(self, lua: *mut lua_State) -> i32 {
-                unsafe { sys::lua_pushinteger(lua, *self as sys::lua_Integer) };
-                1
+                (*self).push_to_lua(lua)
             }
 
             fn box_push_to_lua(self: Box<Self>, lua: *mut lua_State) -> i32
@@ -36,14 +95,55 @@ macro_rules! integer_impl(
             }
         }
 
+        /// out of range values read as `None`, use `Wrapping` or `Saturating` to opt out
         impl LuaRead for $t {
             fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop: i32) -> Option<$t> {
-                let mut success = 0;
-                let val = unsafe { sys::lua_tointegerx(lua, index, &mut success) };
-                match success {
-                    0 => None,
-                    _ => Some(val as $t)
+                read_integer(lua, index).and_then(|v| <$t>::try_from(v).ok())
+            }
+        }
+
+        impl LuaRead for Strict<$t> {
+            fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, pop: i32) -> Option<Strict<$t>> {
+                if unsafe { sys::lua_type(lua, index) != sys::LUA_TNUMBER || sys::lua_isinteger(lua, index) == 0 } {
+                    return None;
                 }
+                <$t>::lua_read_with_pop_impl(lua, index, pop).map(Strict)
+            }
+        }
+
+        /// pushed bit for bit as a `lua_Integer`, so a `Wrapping<u64>` id round-trips exactly,
+        /// wider values keep their low bits
+        impl LuaPush for Wrapping<$t> {
+            fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+                unsafe { sys::lua_pushinteger(lua, self.0 as sys::lua_Integer) };
+                1
+            }
+            impl_box_push!();
+        }
+
+        impl LuaRead for Wrapping<$t> {
+            fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop: i32) -> Option<Wrapping<$t>> {
+                read_integer(lua, index).map(|v| Wrapping(v as $t))
+            }
+        }
+
+        impl LuaPush for Saturating<$t> {
+            fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+                let val = sys::lua_Integer::try_from(self.0).unwrap_or(if self.0 > 0 as $t {
+                    sys::lua_Integer::MAX
+                } else {
+                    sys::lua_Integer::MIN
+                });
+                unsafe { sys::lua_pushinteger(lua, val) };
+                1
+            }
+            impl_box_push!();
+        }
+
+        impl LuaRead for Saturating<$t> {
+            fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop: i32) -> Option<Saturating<$t>> {
+                let val = read_integer(lua, index)?;
+                Some(Saturating(<$t>::try_from(val).unwrap_or(if val > 0 { <$t>::MAX } else { <$t>::MIN })))
             }
         }
     );
@@ -53,12 +153,52 @@ integer_impl!(i8);
 integer_impl!(i16);
 integer_impl!(i32);
 integer_impl!(i64);
+integer_impl!(i128);
+integer_impl!(isize);
 integer_impl!(u8);
 integer_impl!(u16);
 integer_impl!(u32);
 integer_impl!(u64);
+integer_impl!(u128);
 integer_impl!(usize);
 
+macro_rules! nonzero_impl(
+    ($t:ident) => (
+        impl LuaPush for $t {
+            fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+                self.get().push_to_lua(lua)
+            }
+            impl_box_push!();
+        }
+
+        impl LuaPush for &$t {
+            fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+                self.get().push_to_lua(lua)
+            }
+            impl_box_push!();
+        }
+
+        impl LuaRead for $t {
+            fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, pop: i32) -> Option<$t> {
+                LuaRead::lua_read_with_pop_impl(lua, index, pop).and_then(<$t>::new)
+            }
+        }
+    );
+);
+
+nonzero_impl!(NonZeroI8);
+nonzero_impl!(NonZeroI16);
+nonzero_impl!(NonZeroI32);
+nonzero_impl!(NonZeroI64);
+nonzero_impl!(NonZeroI128);
+nonzero_impl!(NonZeroIsize);
+nonzero_impl!(NonZeroU8);
+nonzero_impl!(NonZeroU16);
+nonzero_impl!(NonZeroU32);
+nonzero_impl!(NonZeroU64);
+nonzero_impl!(NonZeroU128);
+nonzero_impl!(NonZeroUsize);
+
 macro_rules! numeric_impl(
     ($t:ident) => (
         impl LuaPush for $t {
@@ -94,21 +234,120 @@ macro_rules! numeric_impl(
                 }
             }
         }
+
+        impl LuaRead for Strict<$t> {
+            fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, pop: i32) -> Option<Strict<$t>> {
+                if unsafe { sys::lua_type(lua, index) != sys::LUA_TNUMBER } {
+                    return None;
+                }
+                <$t>::lua_read_with_pop_impl(lua, index, pop).map(Strict)
+            }
+        }
     );
 );
 
 numeric_impl!(f32);
 numeric_impl!(f64);
 
+/// Borrowed view of a Lua string, read without copying.
+///
+/// The bytes point into the Lua string and are valid as long as the value stays on the
+/// stack, so a view is only handed out by `LuaGuard::read_str`, its lifetime is checked
+/// against the guard that keeps the value alive.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LuaStr<'a>(pub(crate) &'a [u8]);
+
+impl<'a> LuaStr<'a> {
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    pub fn to_str(&self) -> Result<&'a str, std::str::Utf8Error> {
+        std::str::from_utf8(self.0)
+    }
+
+    pub fn to_string_lossy(&self) -> Cow<'a, str> {
+        String::from_utf8_lossy(self.0)
+    }
+}
+
+impl<'a> Deref for LuaStr<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+impl<'a> Debug for LuaStr<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_string_lossy().fmt(f)
+    }
+}
+
+/// the bytes of the string at `index`, only real strings are accepted: converting a
+/// number would replace the value on the stack. the slice is only valid while the string
+/// stays on the stack, the caller picks the lifetime
+pub(crate) fn read_lstring<'a>(lua: *mut lua_State, index: i32) -> Option<&'a [u8]> {
+    unsafe {
+        if sys::lua_type(lua, index) != sys::LUA_TSTRING {
+            return None;
+        }
+        let mut size: libc::size_t = 0;
+        let data = sys::lua_tolstring(lua, index, &mut size);
+        if data.is_null() {
+            return None;
+        }
+        Some(std::slice::from_raw_parts(data as *const u8, size))
+    }
+}
+
+/// the bytes of the string or number at `index`. a number is converted on a copy,
+/// `lua_tolstring` would otherwise turn the slot itself into a string
+fn with_coerced_lstring<R, F>(lua: *mut lua_State, index: i32, f: F) -> Option<R>
+where
+    F: FnOnce(&[u8]) -> R,
+{
+    unsafe {
+        match sys::lua_type(lua, index) {
+            sys::LUA_TSTRING => read_lstring(lua, index).map(f),
+            sys::LUA_TNUMBER => {
+                sys::lua_pushvalue(lua, index);
+                let _guard = LuaGuard::new(lua, 1);
+                let mut size: libc::size_t = 0;
+                let data = sys::lua_tolstring(lua, -1, &mut size);
+                if data.is_null() {
+                    return None;
+                }
+                Some(f(std::slice::from_raw_parts(data as *const u8, size)))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn push_lstring(lua: *mut lua_State, bytes: &[u8]) -> i32 {
+    unsafe { sys::lua_pushlstring(lua, bytes.as_ptr() as *const libc::c_char, bytes.len()) };
+    1
+}
+
+impl<'a> LuaPush for LuaStr<'a> {
+    fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+        push_lstring(lua, self.0)
+    }
+    impl_box_push!();
+}
+
+impl<'a> LuaPush for Cow<'a, str> {
+    fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+        push_lstring(lua, self.as_bytes())
+    }
+    impl_box_push!();
+}
+
 impl LuaPush for &String {
     fn push_to_lua(self, lua: *mut lua_State) -> i32 {
-        if let Some(value) = CString::new(&self[..]).ok() {
-            unsafe { sys::lua_pushstring(lua, value.as_ptr()) };
-            1
-        } else {
-            unsafe { sys::lua_pushstring(lua, cstr!("UNVAILED STRING")) };
-            1
-        }
+        push_lstring(lua, self.as_bytes())
     }
     impl_box_push!();
 }
@@ -120,18 +359,19 @@ impl LuaPush for String {
     impl_box_push!();
 }
 
+/// numbers are converted to their string form, non UTF-8 data reads as `None`,
+/// use `RawString` or `LuaStr` for binary data
 impl LuaRead for String {
     fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop: i32) -> Option<String> {
-        if unsafe { sys::lua_isstring(lua, index) == 0 } {
-            return None;
-        }
-        let mut size = 0;
-        let data = unsafe { sys::lua_tolstring(lua, index, &mut size) };
-        let bytes = unsafe { std::slice::from_raw_parts(data as *const u8, size) };
-        match std::str::from_utf8(bytes) {
-            Ok(v) => Some(v.to_string()),
-            Err(_) => Some("raw binary string...".to_string()),
-        }
+        with_coerced_lstring(lua, index, |v| std::str::from_utf8(v).ok().map(|v| v.to_string()))?
+    }
+}
+
+impl LuaRead for Strict<String> {
+    fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop: i32) -> Option<Strict<String>> {
+        read_lstring(lua, index)
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .map(|v| Strict(v.to_string()))
     }
 }
 
@@ -152,25 +392,16 @@ impl LuaPush for CString {
 
 impl LuaRead for CString {
     fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop: i32) -> Option<CString> {
-        let mut size = 0;
-        let data = unsafe { sys::lua_tolstring(lua, index, &mut size) };
-        let bytes = unsafe { std::slice::from_raw_parts(data as *const u8, size) };
-        match std::str::from_utf8(bytes) {
+        with_coerced_lstring(lua, index, |v| match std::str::from_utf8(v) {
             Ok(v) => CString::new(v).ok(),
             Err(_) => None,
-        }
+        })?
     }
 }
 
 impl<'s> LuaPush for &'s str {
     fn push_to_lua(self, lua: *mut lua_State) -> i32 {
-        if let Some(value) = CString::new(&self[..]).ok() {
-            unsafe { sys::lua_pushstring(lua, value.as_ptr()) };
-            1
-        } else {
-            unsafe { sys::lua_pushstring(lua, cstr!("UNVAILED STRING")) };
-            1
-        }
+        push_lstring(lua, self.as_bytes())
     }
     impl_box_push!();
 }
@@ -201,6 +432,12 @@ impl LuaRead for bool {
     }
 }
 
+impl LuaRead for Strict<bool> {
+    fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, pop: i32) -> Option<Strict<bool>> {
+        bool::lua_read_with_pop_impl(lua, index, pop).map(Strict)
+    }
+}
+
 impl LuaPush for () {
     fn push_to_lua(self, lua: *mut lua_State) -> i32 {
         unsafe { sys::lua_pushnil(lua) };
@@ -223,36 +460,64 @@ impl LuaRead for () {
     }
 }
 
+impl From<Vec<u8>> for RawString {
+    fn from(val: Vec<u8>) -> RawString {
+        RawString(val)
+    }
+}
+
+impl From<RawString> for Vec<u8> {
+    fn from(val: RawString) -> Vec<u8> {
+        val.0
+    }
+}
+
 impl LuaPush for &RawString {
     fn push_to_lua(self, lua: *mut lua_State) -> i32 {
-        unsafe { sys::lua_pushlstring(lua, self.0.as_ptr() as *const i8, self.0.len()) };
-        1
+        push_lstring(lua, &self.0)
     }
     impl_box_push!();
 }
 
 impl LuaPush for RawString {
     fn push_to_lua(self, lua: *mut lua_State) -> i32 {
-        unsafe { sys::lua_pushlstring(lua, self.0.as_ptr() as *const i8, self.0.len()) };
-        1
+        push_lstring(lua, &self.0)
     }
     impl_box_push!();
 }
 
 impl LuaRead for RawString {
     fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop: i32) -> Option<RawString> {
-        let mut size: libc::size_t = 0;
-        let c_str_raw = unsafe { sys::lua_tolstring(lua, index, &mut size) };
-        if c_str_raw.is_null() {
-            return None;
-        }
+        with_coerced_lstring(lua, index, |v| RawString(v.to_vec()))
+    }
+}
 
-        unsafe {
-            let mut dst: Vec<u8> = Vec::with_capacity(size);
-            ptr::copy(c_str_raw as *mut u8, dst.as_mut_ptr(), size);
-            dst.set_len(size);
-            Some(RawString(dst))
-        }
+impl LuaRead for Strict<RawString> {
+    fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop: i32) -> Option<Strict<RawString>> {
+        read_lstring(lua, index).map(|v| Strict(RawString(v.to_vec())))
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl LuaPush for bytes::Bytes {
+    fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+        push_lstring(lua, &self)
+    }
+    impl_box_push!();
+}
+
+#[cfg(feature = "bytes")]
+impl LuaPush for &bytes::Bytes {
+    fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+        push_lstring(lua, self)
+    }
+    impl_box_push!();
+}
+
+#[cfg(feature = "bytes")]
+impl LuaRead for bytes::Bytes {
+    fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _pop: i32) -> Option<bytes::Bytes> {
+        read_lstring(lua, index).map(bytes::Bytes::copy_from_slice)
     }
 }
 
@@ -265,6 +530,15 @@ impl<T: LuaPush> LuaPush for Option<T> {
             1
         }
     }
+
+    fn push_packed_to_lua(self, lua: *mut lua_State) -> i32 {
+        if let Some(v) = self {
+            v.push_packed_to_lua(lua)
+        } else {
+            unsafe { lua_pushnil(lua) };
+            1
+        }
+    }
     impl_box_push!();
 }
 
@@ -303,34 +577,38 @@ impl<T: LuaRead> LuaRead for Option<T> {
     fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, pop: i32) -> Option<Self> {
         Some(T::lua_read_with_pop_impl(lua, index, pop))
     }
+
+    fn lua_read_slots() -> i32 {
+        T::lua_read_slots()
+    }
+
+    fn lua_read_packed(lua: *mut lua_State, index: i32) -> Option<Self> {
+        Some(T::lua_read_packed(lua, index))
+    }
 }
 
 impl<T: LuaPush, E: Debug> LuaPush for Result<T, E> {
     fn push_to_lua(self, lua: *mut lua_State) -> i32 {
+        let ret = self.try_push_to_lua(lua);
+        crate::protocol::raise_on_error(lua, ret)
+    }
+
+    fn try_push_to_lua(self, lua: *mut lua_State) -> Result<i32, String> {
         match self {
-            Ok(t) => t.push_to_lua(lua),
-            Err(e) => {
-                crate::Lua::lua_error(lua, format!("序列化错误:{:?}", e));
-                unreachable!()
-            }
+            Ok(t) => t.try_push_to_lua(lua),
+            Err(e) => Err(format!("序列化错误:{:?}", e)),
         }
     }
 }
 
 impl<T: Serialize> LuaPush for WrapSerde<T> {
     fn push_to_lua(self, lua: *mut lua_State) -> i32 {
-        let mut buffer = unwrap_or!(hcproto::to_buffer(&self.value).ok(), return 0);
-        if let Ok(mut val) = hcproto::decode_msg(&mut buffer) {
-            let mut map = HashMap::new();
-            while val.len() >= 2 {
-                let v = val.pop().unwrap();
-                let k = val.pop().unwrap();
-                map.insert(k, v);
+        match LuaSerializer::to_lua(lua, &self.value) {
+            Ok(size) => size,
+            Err(e) => {
+                warn!("WrapSerde push failed: {}", e);
+                0
             }
-            LuaWrapperValue(Value::Map(map)).push_to_lua(lua);
-            return 1;
-        } else {
-            return 0;
         }
     }
 
@@ -339,14 +617,15 @@ impl<T: Serialize> LuaPush for WrapSerde<T> {
 
 impl<'a, T: Deserialize<'a>> LuaRead for WrapSerde<T> {
     fn lua_read_with_pop_impl(lua: *mut lua_State, index: i32, _: i32) -> Option<Self> {
-        let buffer = unwrap_or!(ProtoLua::ser_protocol(lua, index), return None);
-        let ret = hcproto::from_buffer(buffer);
-        match ret {
+        if unsafe { sys::lua_isnoneornil(lua, index) } {
+            return None;
+        }
+        match LuaDeserializer::from_lua(lua, index) {
             Err(e) => {
-                crate::Lua::lua_error(lua, format!("序列化错误:{:?}", e));
-                return None;
+                warn!("WrapSerde read failed: {}", e);
+                None
             }
-            Ok(v) => return Some(WrapSerde::new(v)),
+            Ok(v) => Some(WrapSerde::new(v)),
         }
     }
 }
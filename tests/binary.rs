@@ -0,0 +1,19 @@
+use hclua::{Lua, LuaBinary};
+
+#[test]
+fn gc_called_by_hand_drops_once() {
+    let mut lua = Lua::new();
+    lua.openlibs();
+    lua.set("b", LuaBinary(vec![1, 2, 3]));
+    assert_eq!(lua.exec_string::<_, i64>("return #b"), Some(3));
+    lua.exec_string::<_, ()>("local gc = getmetatable(b).__gc gc(b) gc(b)")
+        .unwrap();
+    // the dropped value is no longer a binary
+    assert!(lua.query::<LuaBinary, _>("b").is_none());
+    assert_eq!(
+        lua.exec_string::<_, bool>("return pcall(function() return #b end)"),
+        Some(false)
+    );
+    lua.exec_string::<_, ()>("b = nil collectgarbage()")
+        .unwrap();
+}
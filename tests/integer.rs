@@ -0,0 +1,74 @@
+use std::num::{Saturating, Wrapping};
+
+use hclua::{Lua, Strict};
+
+#[test]
+fn read_out_of_range() {
+    let mut lua = Lua::new();
+    lua.set("a", -1);
+    lua.set("b", 300);
+    lua.set("c", 3.5);
+    lua.set("d", 3.0);
+    assert_eq!(lua.query::<u32, _>("a"), None);
+    assert_eq!(lua.query::<u8, _>("b"), None);
+    assert_eq!(lua.query::<i64, _>("c"), None);
+    assert_eq!(lua.query::<i64, _>("d"), Some(3));
+    assert_eq!(lua.query::<Wrapping<u32>, _>("a"), Some(Wrapping(u32::MAX)));
+    assert_eq!(
+        lua.query::<Saturating<u8>, _>("b"),
+        Some(Saturating(u8::MAX))
+    );
+    assert_eq!(lua.query::<Saturating<u8>, _>("a"), Some(Saturating(0)));
+}
+
+#[test]
+fn wide_integers_are_refused() {
+    let mut lua = Lua::new();
+    lua.set("a", u64::MAX);
+    lua.set("b", i128::MIN);
+    lua.set("c", u128::MAX);
+    // a float would lose digits and a string would change the type, nil is pushed instead
+    assert_eq!(lua.query::<u64, _>("a"), None);
+    assert_eq!(lua.query::<i128, _>("b"), None);
+    assert_eq!(lua.query::<u128, _>("c"), None);
+    assert_eq!(lua.exec_string::<_, bool>("return a == nil"), Some(true));
+
+    lua.set("d", i64::MAX as u64);
+    lua.set("e", i64::MIN as i128);
+    assert_eq!(lua.query::<u64, _>("d"), Some(i64::MAX as u64));
+    assert_eq!(
+        lua.query::<Strict<u64>, _>("d"),
+        Some(Strict(i64::MAX as u64))
+    );
+    assert_eq!(lua.query::<i128, _>("e"), Some(i64::MIN as i128));
+}
+
+#[test]
+fn wide_integers_raise_from_functions() {
+    let mut lua = Lua::new();
+    lua.openlibs();
+    lua.set("wide", hclua::function0(|| u64::MAX));
+    lua.set("narrow", hclua::function0(|| 7u64));
+    assert_eq!(
+        lua.exec_string::<_, bool>("return pcall(wide)"),
+        Some(false)
+    );
+    assert_eq!(lua.exec_string::<_, u64>("return narrow()"), Some(7));
+}
+
+#[test]
+fn wrapping_keeps_the_bits() {
+    let mut lua = Lua::new();
+    lua.set("a", Wrapping(u64::MAX));
+    assert_eq!(lua.query::<i64, _>("a"), Some(-1));
+    assert_eq!(lua.query::<Wrapping<u64>, _>("a"), Some(Wrapping(u64::MAX)));
+
+    lua.set("b", Wrapping(u128::MAX));
+    lua.set("c", Wrapping(i128::MIN));
+    assert_eq!(
+        lua.query::<Wrapping<u128>, _>("b"),
+        Some(Wrapping(u128::MAX))
+    );
+    // only the low bits of a wider value are kept
+    assert_eq!(lua.query::<Wrapping<i128>, _>("c"), Some(Wrapping(0)));
+}
@@ -0,0 +1,45 @@
+#![cfg(feature = "json")]
+
+use hclua::{sys, Lua};
+
+#[test]
+fn rust_round_trip() {
+    let mut lua = Lua::new();
+    let state = lua.state();
+    let top = unsafe { sys::lua_gettop(state) };
+    assert_eq!(lua.from_json(r#"{"a":[1,2.5,"x"],"b":[],"c":{}}"#), Ok(1));
+    assert_eq!(
+        lua.to_json(-1).unwrap(),
+        r#"{"a":[1,2.5,"x"],"b":[],"c":{}}"#
+    );
+    unsafe { sys::lua_settop(state, top) };
+    assert!(lua.from_json("{").is_err());
+    assert_eq!(unsafe { sys::lua_gettop(state) }, top);
+}
+
+#[test]
+fn script_module() {
+    let mut lua = Lua::new();
+    lua.openlibs();
+    lua.requiref("json", hclua::luaopen_json, true);
+    assert_eq!(
+        lua.exec_string::<_, String>("return json.encode({1, 2, json.array()})"),
+        Some("[1,2,[]]".into())
+    );
+    assert_eq!(
+        lua.exec_string::<_, bool>(
+            "local t = json.decode('[1, null, 3]', { null = true }) \
+             return t[2] == json.null and #t == 3"
+        ),
+        Some(true)
+    );
+    assert_eq!(
+        lua.exec_string::<_, bool>("return pcall(json.decode, '{')"),
+        Some(false)
+    );
+    // functions are encoded as null by default
+    assert_eq!(
+        lua.exec_string::<_, String>("return json.encode({print})"),
+        Some("[null]".into())
+    );
+}
@@ -0,0 +1,101 @@
+use std::fmt;
+use std::ops::{Add, Neg};
+
+use hclua::{Lua, MetaMethod, Owned, UserData};
+
+#[derive(Default, Clone, Copy, PartialEq, PartialOrd)]
+struct Money(i64);
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "${}", self.0)
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        Money(self.0 + other.0)
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money(-self.0)
+    }
+}
+
+impl UserData for Money {
+    fn add_methods(methods: &mut hclua::UserDataMethods<Self>) {
+        methods.add_method("value", |m, ()| m.0);
+    }
+
+    fn add_meta_methods(methods: &mut hclua::UserDataMetaMethods<Self>) {
+        methods
+            .derive_display()
+            .derive_eq()
+            .derive_ord()
+            .derive_add()
+            .derive_neg();
+        methods.add_meta_method(MetaMethod::Len, |m, ()| m.0.unsigned_abs());
+        methods.add_meta_method(MetaMethod::Call, |m, times: i64| m.0 * times);
+        methods.add_meta_function(MetaMethod::Concat, |(text, m): (String, &Money)| {
+            format!("{}{}", text, m)
+        });
+        // `__index` belongs to the dispatch and is refused
+        methods.add_meta_method("__index", |_, ()| 0);
+    }
+}
+
+fn money_state() -> Lua {
+    let mut lua = Lua::new();
+    lua.openlibs();
+    lua.set("a", Owned(Money(3)));
+    lua.set("b", Owned(Money(4)));
+    lua.set("c", Owned(Money(3)));
+    lua
+}
+
+#[test]
+fn derived_operators() {
+    let mut lua = money_state();
+    assert_eq!(
+        lua.exec_string::<_, String>("return tostring(a + b)"),
+        Some("$7".into())
+    );
+    assert_eq!(lua.exec_string::<_, i64>("return (-a):value()"), Some(-3));
+    assert_eq!(
+        lua.exec_string::<_, bool>("return a == c and a ~= b and a < b and b >= c"),
+        Some(true)
+    );
+    // an object is never equal to another type, ordering against one is an error
+    assert_eq!(lua.exec_string::<_, bool>("return a == 3"), Some(false));
+    assert_eq!(
+        lua.exec_string::<_, bool>("return pcall(function() return a < 3 end)"),
+        Some(false)
+    );
+    assert_eq!(
+        lua.exec_string::<_, bool>("return pcall(function() return a + 1 end)"),
+        Some(false)
+    );
+}
+
+#[test]
+fn custom_metamethods() {
+    let mut lua = money_state();
+    assert_eq!(lua.exec_string::<_, i64>("return #(-b)"), Some(4));
+    assert_eq!(lua.exec_string::<_, i64>("return a(5)"), Some(15));
+    assert_eq!(
+        lua.exec_string::<_, String>("return 'cost ' .. a"),
+        Some("cost $3".into())
+    );
+    // the operands are read as the function declares them
+    assert_eq!(
+        lua.exec_string::<_, bool>("return pcall(function() return a .. 'x' end)"),
+        Some(false)
+    );
+    assert_eq!(lua.exec_string::<_, i64>("return a:value()"), Some(3));
+}
@@ -0,0 +1,110 @@
+#![cfg(feature = "msgpack")]
+
+use hclua::{sys, Lua, MsgPackLua, SerdeOptions};
+use rmpv::Value;
+
+fn encode(value: &Value) -> Vec<u8> {
+    let mut data = Vec::new();
+    rmpv::encode::write_value(&mut data, value).unwrap();
+    data
+}
+
+fn nested(depth: usize) -> Value {
+    (0..depth).fold(Value::from(1), |v, _| Value::Array(vec![v]))
+}
+
+#[test]
+fn unpack_values() {
+    let mut lua = Lua::new();
+    let state = lua.state();
+    let map = Value::Map(vec![
+        (Value::from("a"), Value::from(1)),
+        (Value::from(2), Value::Array(vec![Value::from(true)])),
+    ]);
+    let mut data = encode(&map);
+    data.extend(encode(&Value::from("x")));
+    assert_eq!(MsgPackLua::unpack(state, &data).unwrap(), 2);
+    unsafe {
+        sys::lua_setglobal(state, c"b".as_ptr());
+        sys::lua_setglobal(state, c"a".as_ptr());
+    }
+    assert_eq!(
+        lua.exec_string::<_, bool>("return a.a == 1 and a[2][1] and b == 'x'"),
+        Some(true)
+    );
+}
+
+#[test]
+fn unpack_checks_depth() {
+    let mut lua = Lua::new();
+    let state = lua.state();
+    let top = unsafe { sys::lua_gettop(state) };
+    let data = encode(&nested(50));
+    assert_eq!(MsgPackLua::unpack(state, &data).unwrap(), 1);
+    unsafe { sys::lua_settop(state, top) };
+
+    let data = encode(&nested(1000));
+    assert!(MsgPackLua::unpack(state, &data).is_err());
+    let options = SerdeOptions::new().max_depth(10);
+    assert!(MsgPackLua::unpack_with(state, &encode(&nested(20)), &options).is_err());
+    assert_eq!(unsafe { sys::lua_gettop(state) }, top);
+}
+
+#[test]
+fn unpack_refuses_bad_keys() {
+    let mut lua = Lua::new();
+    let state = lua.state();
+    let top = unsafe { sys::lua_gettop(state) };
+    for key in [Value::Nil, Value::from(f64::NAN)] {
+        let map = Value::Map(vec![
+            (Value::from(1), Value::from(1)),
+            (key, Value::from(2)),
+        ]);
+        let data = encode(&Value::Array(vec![map]));
+        assert!(MsgPackLua::unpack(state, &data).is_err());
+        assert_eq!(unsafe { sys::lua_gettop(state) }, top);
+    }
+}
+
+#[test]
+fn pack_keeps_integral_floats() {
+    let mut lua = Lua::new();
+    let state = lua.state();
+    let top = unsafe { sys::lua_gettop(state) };
+    lua.exec_string::<_, ()>("v = {3.0, 3, -0.0}").unwrap();
+    unsafe { sys::lua_getglobal(state, c"v".as_ptr()) };
+    let data = MsgPackLua::pack(state, -1).unwrap();
+    unsafe { sys::lua_settop(state, top) };
+    let value = rmpv::decode::read_value(&mut &data[..]).unwrap();
+    let items = value.as_array().unwrap();
+    assert_eq!(items[0].as_f64(), Some(3.0));
+    assert!(items[0].is_f64());
+    assert_eq!(items[1].as_i64(), Some(3));
+    assert!(items[2].is_f64());
+}
+
+#[test]
+fn pack_keeps_key_types() {
+    let mut lua = Lua::new();
+    let state = lua.state();
+    let top = unsafe { sys::lua_gettop(state) };
+    lua.exec_string::<_, ()>("m = {[-1] = 'a', [1.5] = 'b', [true] = 'c', [7] = 'd', [2.0] = 'e'}")
+        .unwrap();
+    unsafe { sys::lua_getglobal(state, c"m".as_ptr()) };
+    let data = MsgPackLua::pack(state, -1).unwrap();
+    unsafe { sys::lua_settop(state, top) };
+    let value = rmpv::decode::read_value(&mut &data[..]).unwrap();
+    let map = value.as_map().unwrap();
+    let key_of = |v: &str| {
+        map.iter()
+            .find(|(_, val)| val.as_str() == Some(v))
+            .map(|(key, _)| key.clone())
+            .unwrap()
+    };
+    assert_eq!(key_of("a").as_i64(), Some(-1));
+    assert!(key_of("b").is_f64());
+    assert_eq!(key_of("c").as_bool(), Some(true));
+    assert_eq!(key_of("d").as_u64(), Some(7));
+    // Lua already turned the integral float key into an integer
+    assert_eq!(key_of("e").as_u64(), Some(2));
+}
@@ -0,0 +1,162 @@
+use hclua::{sys, FieldType, Lua, ProtoLua, Schema};
+use serde::Serialize;
+
+#[derive(Serialize, Default)]
+struct Login {
+    name: String,
+    level: u32,
+    items: Vec<u32>,
+    guild: Option<String>,
+}
+
+fn open_proto(lua: &mut Lua) {
+    lua.openlibs();
+    lua.requiref("proto", hclua::luaopen_proto, true);
+}
+
+#[test]
+fn from_serde_infers_fields() {
+    let schema = Schema::from_serde("Login", &Login::default()).unwrap();
+    let names: Vec<_> = schema.fields.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, ["guild", "items", "level", "name"]);
+    assert!(!schema.fields[0].required);
+    assert_eq!(
+        schema.fields[1].ty,
+        FieldType::Array(Box::new(FieldType::Any))
+    );
+    assert_eq!(schema.fields[2].ty, FieldType::Integer);
+    assert_eq!(schema.fields[3].ty, FieldType::Str);
+}
+
+#[derive(Serialize)]
+struct Stats {
+    speed: f64,
+    tags: Vec<String>,
+    id: u64,
+}
+
+#[test]
+fn from_serde_follows_the_lua_serializer() {
+    let stats = Stats {
+        speed: 3.0,
+        tags: vec!["a".into()],
+        id: 1,
+    };
+    let schema = Schema::from_serde("Stats", &stats).unwrap();
+    let types: Vec<_> = schema.fields.iter().map(|f| f.ty.clone()).collect();
+    assert_eq!(
+        types,
+        [
+            FieldType::Integer,
+            FieldType::Number,
+            FieldType::Array(Box::new(FieldType::Str))
+        ]
+    );
+    // the serializer refuses what it can't push, the schema too
+    let wide = Stats {
+        id: u64::MAX,
+        ..stats
+    };
+    assert!(Schema::from_serde("Stats", &wide).is_err());
+}
+
+#[test]
+fn schemas_belong_to_their_state() {
+    let mut lua = Lua::new();
+    let mut other = Lua::new();
+    open_proto(&mut lua);
+    open_proto(&mut other);
+    Schema::from_serde("Login", &Login::default())
+        .unwrap()
+        .register(lua.state())
+        .unwrap();
+    assert!(Schema::get(lua.state(), "Login").is_some());
+    assert!(Schema::get(other.state(), "Login").is_none());
+
+    let ok: Option<bool> = lua.exec_string(
+        "local login = proto.new('Login') login.name = 'hc' return proto.validate(login)",
+    );
+    assert_eq!(ok, Some(true));
+    let ok: Option<bool> = other.exec_string("return pcall(proto.new, 'Login')");
+    assert_eq!(ok, Some(false));
+}
+
+#[test]
+fn validate_names_the_field() {
+    let mut lua = Lua::new();
+    open_proto(&mut lua);
+    Schema::declare("Item", &[("id", "integer")])
+        .register(lua.state())
+        .unwrap();
+    Schema::declare("Bag", &[("items", "[Item]")])
+        .register(lua.state())
+        .unwrap();
+    let err: Option<String> = lua.exec_string(
+        "local bag = proto.new('Bag') bag.items = { { id = 1 }, { id = 'x' } } \
+         return select(2, proto.validate(bag))",
+    );
+    assert_eq!(
+        err.as_deref(),
+        Some("Bag.items[2].id: expected integer, found string")
+    );
+
+    let state = lua.state();
+    let top = unsafe { sys::lua_gettop(state) };
+    lua.exec_string::<_, ()>("bag = proto.new('Bag') bag.extra = 1");
+    unsafe { sys::lua_getglobal(state, c"bag".as_ptr()) };
+    let schema = Schema::get(state, "Bag");
+    assert!(ProtoLua::try_ser_protocol(state, -1, schema.as_deref()).is_err());
+    unsafe { sys::lua_settop(state, top) };
+}
+
+#[test]
+fn recursive_messages_are_refused() {
+    let mut lua = Lua::new();
+    open_proto(&mut lua);
+    Schema::declare("Node", &[("value", "integer"), ("next", "Node")])
+        .register(lua.state())
+        .unwrap();
+    Schema::declare("List", &[("head", "Node?")])
+        .register(lua.state())
+        .unwrap();
+    let top = unsafe { sys::lua_gettop(lua.state()) };
+    let node = Schema::get(lua.state(), "Node").unwrap();
+    assert!(node.push_new(lua.state()).is_err());
+    assert_eq!(unsafe { sys::lua_gettop(lua.state()) }, top);
+
+    let ok: Option<bool> = lua.exec_string("return pcall(proto.new, 'Node')");
+    assert_eq!(ok, Some(false));
+    let ok: Option<bool> = lua.exec_string("return proto.new('List').head == nil");
+    assert_eq!(ok, Some(true));
+}
+
+#[test]
+fn nul_names_are_refused() {
+    let mut lua = Lua::new();
+    assert!(Schema::declare("Bad\0", &[("id", "integer")])
+        .register(lua.state())
+        .is_err());
+    assert!(Schema::declare("Bad", &[("i\0d", "integer")])
+        .register(lua.state())
+        .is_err());
+    assert!(Schema::get(lua.state(), "Bad").is_none());
+}
+
+#[test]
+fn ser_protocol_reports_instead_of_raising() {
+    let mut lua = Lua::new();
+    open_proto(&mut lua);
+    Schema::declare("Item", &[("id", "integer")])
+        .register(lua.state())
+        .unwrap();
+    lua.exec_string::<_, ()>("item = proto.new('Item') item.id = 'x'");
+    let state = lua.state();
+    let top = unsafe { sys::lua_gettop(state) };
+    unsafe { sys::lua_getglobal(state, c"item".as_ptr()) };
+    assert!(ProtoLua::ser_protocol(state, -1).is_none());
+    assert!(ProtoLua::try_ser_protocol(state, -1, None).is_ok());
+    unsafe { sys::lua_pushinteger(state, 1) };
+    assert!(ProtoLua::ser_protocol(state, -1).is_none());
+    assert!(ProtoLua::try_ser_protocol(state, -1, None).is_err());
+    unsafe { sys::lua_settop(state, top) };
+}
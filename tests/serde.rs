@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+
+use hclua::{
+    sys, ArrayMode, EmptyTable, Lua, LuaDeserializer, LuaSerializer, ProtoLua, SerdeOptions,
+    WrapSerde,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Kind {
+    Idle,
+    Move(i32, i32),
+    Say { text: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Player {
+    id: u64,
+    name: String,
+    score: f64,
+    items: Vec<u32>,
+    empty: Vec<String>,
+    guild: Option<String>,
+    attrs: HashMap<String, i64>,
+    kinds: Vec<Kind>,
+    pos: (i8, u16),
+}
+
+fn player() -> Player {
+    Player {
+        id: i64::MAX as u64,
+        name: "hc".into(),
+        score: 1.5,
+        items: vec![1, 2, 3],
+        empty: vec![],
+        guild: None,
+        attrs: HashMap::from([("hp".to_string(), -10), ("mp".to_string(), 20)]),
+        kinds: vec![
+            Kind::Idle,
+            Kind::Move(1, -1),
+            Kind::Say { text: "hi".into() },
+        ],
+        pos: (-3, 400),
+    }
+}
+
+#[test]
+fn round_trip() {
+    let mut lua = Lua::new();
+    lua.set("player", WrapSerde::new(player()));
+    let read: WrapSerde<Player> = lua.query("player").unwrap();
+    assert_eq!(read.value, player());
+
+    assert_eq!(
+        lua.exec_string::<_, String>("return player.kinds[1]"),
+        Some("Idle".into())
+    );
+    assert_eq!(
+        lua.exec_string::<_, i64>("return player.kinds[2].Move[2]"),
+        Some(-1)
+    );
+    assert_eq!(
+        lua.exec_string::<_, String>("return player.kinds[3].Say.text"),
+        Some("hi".into())
+    );
+}
+
+#[test]
+fn mismatched_values_read_as_none() {
+    let mut lua = Lua::new();
+    lua.exec_string::<_, ()>("bad = { id = 'not a number' }")
+        .unwrap();
+    assert!(lua.query::<WrapSerde<Player>, _>("bad").is_none());
+    // the table is still there to be read as something else
+    assert_eq!(
+        lua.exec_string::<_, String>("return bad.id"),
+        Some("not a number".into())
+    );
+}
+
+#[test]
+fn raw_stack_round_trip() {
+    let mut lua = Lua::new();
+    let state = lua.state();
+    let top = unsafe { sys::lua_gettop(state) };
+    assert_eq!(LuaSerializer::to_lua(state, &player()).unwrap(), 1);
+    let read: Player = LuaDeserializer::from_lua(state, -1).unwrap();
+    assert_eq!(read, player());
+    unsafe { sys::lua_settop(state, top) };
+}
+
+#[test]
+fn out_of_range_integers_are_errors() {
+    let mut lua = Lua::new();
+    let state = lua.state();
+    let top = unsafe { sys::lua_gettop(state) };
+    assert!(LuaSerializer::to_lua(state, &u64::MAX).is_err());
+    assert!(LuaSerializer::to_lua(state, &(i64::MIN as i128 - 1)).is_err());
+    assert!(LuaSerializer::to_lua(state, &vec![1u128, u128::MAX]).is_err());
+    // the stack is left untouched on error
+    assert_eq!(unsafe { sys::lua_gettop(state) }, top);
+
+    assert_eq!(
+        LuaSerializer::to_lua(state, &(i64::MAX as u128)).unwrap(),
+        1
+    );
+    let read: u128 = LuaDeserializer::from_lua(state, -1).unwrap();
+    assert_eq!(read, i64::MAX as u128);
+    unsafe { sys::lua_settop(state, top) };
+}
+
+#[test]
+fn unknown_fields() {
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Small {
+        name: String,
+    }
+
+    let mut lua = Lua::new();
+    lua.exec_string::<_, ()>("t = { name = 'hc', typo = 1 }");
+    let state = lua.state();
+    let top = unsafe { sys::lua_gettop(state) };
+    unsafe { sys::lua_getglobal(state, c"t".as_ptr()) };
+    assert!(LuaDeserializer::from_lua::<Small>(state, -1).is_ok());
+    let options = SerdeOptions::new().deny_unknown_fields(true);
+    assert!(LuaDeserializer::from_lua_with::<Small>(state, -1, &options).is_err());
+    unsafe { sys::lua_settop(state, top) };
+}
+
+fn json_with(lua: &mut Lua, script: &str, options: &SerdeOptions) -> Option<String> {
+    let state = lua.state();
+    let top = unsafe { sys::lua_gettop(state) };
+    lua.exec_string::<_, ()>(script);
+    unsafe { sys::lua_getglobal(state, c"t".as_ptr()) };
+    let json = hclua::to_json(state, -1, options, false).ok();
+    unsafe { sys::lua_settop(state, top) };
+    json
+}
+
+#[test]
+fn array_detection() {
+    let mut lua = Lua::new();
+    hclua::load_serde_helpers(lua.state());
+    let strict = SerdeOptions::new();
+    let any = SerdeOptions::new().array_mode(ArrayMode::Any);
+    let tagged = SerdeOptions::new().array_mode(ArrayMode::Tagged);
+
+    let seq = "t = {1, 2}";
+    assert_eq!(json_with(&mut lua, seq, &strict), Some("[1,2]".into()));
+    assert_eq!(json_with(&mut lua, seq, &any), Some("[1,2]".into()));
+    assert_eq!(
+        json_with(&mut lua, seq, &tagged),
+        Some(r#"{"1":1,"2":2}"#.into())
+    );
+
+    // a border with a hash part is only an array when asked for
+    let mixed = "t = {'a', x = 1}";
+    assert_eq!(
+        json_with(&mut lua, mixed, &strict),
+        Some(r#"{"1":"a","x":1}"#.into())
+    );
+    assert_eq!(json_with(&mut lua, mixed, &any), Some(r#"["a"]"#.into()));
+
+    let marked = "t = hclua.array({1, 2})";
+    assert_eq!(json_with(&mut lua, marked, &tagged), Some("[1,2]".into()));
+
+    let empty = "t = {}";
+    assert_eq!(json_with(&mut lua, empty, &strict), Some("{}".into()));
+    assert_eq!(
+        json_with(&mut lua, empty, &strict.empty_table(EmptyTable::Array)),
+        Some("[]".into())
+    );
+}
+
+#[test]
+fn nil_handling() {
+    #[derive(Serialize)]
+    struct Guild {
+        id: Option<i32>,
+    }
+
+    let mut lua = Lua::new();
+    lua.openlibs();
+    hclua::load_serde_helpers(lua.state());
+    let state = lua.state();
+    let guild = Guild { id: None };
+    LuaSerializer::to_lua(state, &guild).unwrap();
+    unsafe { sys::lua_setglobal(state, c"plain".as_ptr()) };
+    let with_null = SerdeOptions::new().null_sentinel(true);
+    LuaSerializer::to_lua_with(state, &guild, &with_null).unwrap();
+    unsafe { sys::lua_setglobal(state, c"kept".as_ptr()) };
+
+    // a nil entry is dropped from the table, the sentinel keeps it
+    assert_eq!(
+        lua.exec_string::<_, bool>("return next(plain) == nil and kept.id == hclua.null"),
+        Some(true)
+    );
+    // the sentinel reads back as nil
+    let read = lua.query::<WrapSerde<HashMap<String, Option<i32>>>, _>("kept");
+    assert_eq!(read.unwrap().value, HashMap::from([("id".into(), None)]));
+}
+
+#[test]
+fn empty_tables_round_trip() {
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Bag {
+        items: Vec<u32>,
+        attrs: HashMap<String, u32>,
+    }
+
+    let mut lua = Lua::new();
+    lua.openlibs();
+    hclua::load_serde_helpers(lua.state());
+    let bag = Bag {
+        items: vec![],
+        attrs: HashMap::new(),
+    };
+    lua.set("bag", WrapSerde::new(bag));
+    assert_eq!(
+        lua.exec_string::<_, bool>(
+            "return getmetatable(bag.items) == hclua.array_mt and getmetatable(bag.attrs) == nil"
+        ),
+        Some(true)
+    );
+    let read: WrapSerde<Bag> = lua.query("bag").unwrap();
+    assert!(read.value.items.is_empty() && read.value.attrs.is_empty());
+
+    // the marker also survives the protocol
+    let state = lua.state();
+    let top = unsafe { sys::lua_gettop(state) };
+    unsafe {
+        sys::lua_getglobal(state, c"bag".as_ptr());
+        sys::lua_getfield(state, -1, c"items".as_ptr());
+        sys::lua_getfield(state, -2, c"attrs".as_ptr());
+    }
+    let mut buffer = ProtoLua::try_pack_protocol(state, top + 2).unwrap();
+    unsafe { sys::lua_settop(state, top) };
+    assert_eq!(
+        ProtoLua::try_unpack_protocol(state, &mut buffer).unwrap(),
+        1
+    );
+    unsafe { sys::lua_setglobal(state, c"t".as_ptr()) };
+    assert_eq!(
+        lua.exec_string::<_, bool>(
+            "return getmetatable(t[1]) == hclua.array_mt and next(t[2]) == nil \
+             and getmetatable(t[2]) == nil"
+        ),
+        Some(true)
+    );
+}
@@ -0,0 +1,131 @@
+use hclua::{sys, DecodeError, Lua, LuaPush, ProtoDecoder, ProtoLua, FRAME_HEADER};
+
+/// a framed `pack_protocol` message of `values`
+fn pack(lua: &mut Lua, values: (i64, &str)) -> Vec<u8> {
+    let state = lua.state();
+    let top = unsafe { sys::lua_gettop(state) };
+    values.push_to_lua(state);
+    let frame = ProtoLua::pack_frame(state, top + 1).unwrap();
+    unsafe { sys::lua_settop(state, top) };
+    frame
+}
+
+/// pushes the next message as the global `t` and checks it holds `values`
+fn check_next(lua: &mut Lua, decoder: &mut ProtoDecoder, values: (i64, &str)) {
+    let state = lua.state();
+    assert_eq!(decoder.push_next(state), Ok(1));
+    unsafe { sys::lua_setglobal(state, c"t".as_ptr()) };
+    let script = format!("return t[1] == {} and t[2] == '{}'", values.0, values.1);
+    assert_eq!(lua.exec_string::<_, bool>(script), Some(true));
+}
+
+#[test]
+fn partial_feeds() {
+    let mut lua = Lua::new();
+    let mut data = pack(&mut lua, (1, "first"));
+    data.extend(pack(&mut lua, (2, "second")));
+
+    let mut decoder = ProtoDecoder::new();
+    assert_eq!(
+        decoder.decode_next(),
+        Err(DecodeError::NeedMore {
+            needed: FRAME_HEADER
+        })
+    );
+    let mut fed = 0;
+    let mut decoded = 0;
+    for byte in &data {
+        decoder.feed(std::slice::from_ref(byte));
+        fed += 1;
+        match decoder.push_next(lua.state()) {
+            Ok(_) => {
+                decoded += 1;
+                unsafe { sys::lua_setglobal(lua.state(), c"t".as_ptr()) };
+            }
+            Err(DecodeError::NeedMore { needed }) => assert!(fed + needed <= data.len()),
+            Err(e) => panic!("{}", e),
+        }
+    }
+    assert_eq!(decoded, 2);
+    assert_eq!(decoder.pending(), 0);
+    assert_eq!(
+        lua.exec_string::<_, bool>("return t[1] == 2 and t[2] == 'second'"),
+        Some(true)
+    );
+}
+
+#[test]
+fn split_feeds() {
+    let mut lua = Lua::new();
+    let first = pack(&mut lua, (1, "first"));
+    let second = pack(&mut lua, (2, "second"));
+    let mut data = first.clone();
+    data.extend(&second);
+
+    let mut decoder = ProtoDecoder::new();
+    let cut = first.len() + 2;
+    decoder.feed(&data[..cut]);
+    check_next(&mut lua, &mut decoder, (1, "first"));
+    assert!(matches!(
+        decoder.push_next(lua.state()),
+        Err(DecodeError::NeedMore { .. })
+    ));
+    decoder.feed(&data[cut..]);
+    check_next(&mut lua, &mut decoder, (2, "second"));
+}
+
+#[test]
+fn corrupt_messages_are_skipped() {
+    let mut lua = Lua::new();
+    let good = pack(&mut lua, (1, "good"));
+    let mut bad = pack(&mut lua, (2, "bad"));
+    // the frame claims one byte less than the message needs
+    let len = (bad.len() - FRAME_HEADER - 1) as u32;
+    bad.truncate(bad.len() - 1);
+    bad[..FRAME_HEADER].copy_from_slice(&len.to_be_bytes());
+
+    let mut decoder = ProtoDecoder::new();
+    decoder.feed(&good);
+    decoder.feed(&bad);
+    decoder.feed(&good);
+    check_next(&mut lua, &mut decoder, (1, "good"));
+    match decoder.decode_next() {
+        Err(DecodeError::Corrupt { offset, .. }) => {
+            assert!(offset > good.len() + FRAME_HEADER);
+            assert!(offset <= good.len() + bad.len());
+        }
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+    check_next(&mut lua, &mut decoder, (1, "good"));
+    assert_eq!(decoder.pending(), 0);
+}
+
+#[test]
+fn oversized_header_drops_pending() {
+    let mut decoder = ProtoDecoder::new();
+    decoder.feed(&[0xff, 0xff, 0xff, 0xff, 1, 2, 3]);
+    assert!(matches!(
+        decoder.decode_next(),
+        Err(DecodeError::Corrupt { offset: 0, .. })
+    ));
+    assert_eq!(decoder.pending(), 0);
+}
+
+#[test]
+fn feed_buffer() {
+    use std::io::Write;
+
+    let mut lua = Lua::new();
+    let data = pack(&mut lua, (3, "buffer"));
+    let mut buffer = hcproto::Buffer::new();
+    let _ = buffer.write(&data);
+    let mut decoder = ProtoDecoder::new();
+    decoder.feed_buffer(buffer);
+    check_next(&mut lua, &mut decoder, (3, "buffer"));
+
+    let mut buffer = hcproto::Buffer::new();
+    let _ = buffer.write(&data[..5]);
+    decoder.feed_buffer(buffer);
+    decoder.feed(&data[5..]);
+    check_next(&mut lua, &mut decoder, (3, "buffer"));
+}
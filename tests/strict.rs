@@ -0,0 +1,36 @@
+use hclua::{Lua, RawString, Strict};
+
+#[test]
+fn strict_reads_refuse_coercions() {
+    let mut lua = Lua::new();
+    lua.set("s", "12");
+    lua.set("n", 12);
+    lua.set("f", 12.0);
+    lua.set("b", true);
+
+    // the plain reads follow Lua and convert numeric strings and numbers
+    assert_eq!(lua.query::<i64, _>("s"), Some(12));
+    assert_eq!(lua.query::<f64, _>("s"), Some(12.0));
+    assert_eq!(lua.query::<String, _>("n"), Some("12".into()));
+    assert_eq!(
+        lua.query::<RawString, _>("n"),
+        Some(RawString(b"12".to_vec()))
+    );
+
+    assert_eq!(lua.query::<Strict<i64>, _>("s"), None);
+    assert_eq!(lua.query::<Strict<f64>, _>("s"), None);
+    assert_eq!(lua.query::<Strict<String>, _>("n"), None);
+    assert_eq!(lua.query::<Strict<RawString>, _>("n"), None);
+    // a strict integer is a Lua integer, a float with an integral value isn't one
+    assert_eq!(lua.query::<Strict<i64>, _>("f"), None);
+    assert_eq!(lua.query::<Strict<f64>, _>("n"), Some(Strict(12.0)));
+    assert_eq!(lua.query::<Strict<bool>, _>("b"), Some(Strict(true)));
+    assert_eq!(lua.query::<Strict<bool>, _>("n"), None);
+
+    // the values were not converted in place by the reads
+    assert_eq!(
+        lua.query::<Strict<String>, _>("s"),
+        Some(Strict("12".into()))
+    );
+    assert_eq!(lua.query::<Strict<i64>, _>("n"), Some(Strict(12)));
+}
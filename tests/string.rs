@@ -0,0 +1,58 @@
+use std::borrow::Cow;
+
+use hclua::{Lua, RawString, Strict};
+
+#[test]
+fn guard_reads_borrowed_strings() {
+    let mut lua = Lua::new();
+    lua.set("name", "hclua");
+    lua.set("n", 12);
+    let guard = lua.query_guard("name");
+    let name = guard.read_str(-1).unwrap();
+    assert_eq!(name.to_str(), Ok("hclua"));
+    drop(guard);
+
+    // numbers are not converted in place, a borrowed read would change the slot
+    let guard = lua.query_guard("n");
+    assert!(guard.read_str(-1).is_none());
+    drop(guard);
+    assert_eq!(lua.query::<i64, _>("n"), Some(12));
+    assert_eq!(lua.query::<String, _>("n"), Some("12".to_string()));
+    assert_eq!(lua.query::<Strict<String>, _>("n"), None);
+}
+
+#[test]
+fn raw_string_keeps_bytes() {
+    let mut lua = Lua::new();
+    let data = vec![0u8, 0xff, b'a', 0, 0x80];
+    lua.set("data", RawString(data.clone()));
+    assert_eq!(
+        lua.query::<RawString, _>("data"),
+        Some(RawString(data.clone()))
+    );
+    assert_eq!(lua.query::<String, _>("data"), None);
+    assert_eq!(lua.exec_string::<_, i64>("return #data"), Some(5));
+
+    let guard = lua.query_guard("data");
+    assert_eq!(guard.read_str(-1).unwrap().as_bytes(), &data[..]);
+    assert_eq!(guard.read_bytes(-1), Some(&data[..]));
+    // not UTF-8, the text is a copy with the bad bytes replaced
+    assert!(matches!(guard.read_cow(-1), Some(Cow::Owned(_))));
+}
+
+#[test]
+fn guard_reads_borrowed_text() {
+    let mut lua = Lua::new();
+    lua.set("name", "hclua");
+    lua.set("n", 12);
+    let guard = lua.query_guard("name");
+    assert_eq!(guard.read_bytes(-1), Some(&b"hclua"[..]));
+    assert!(matches!(guard.read_cow(-1), Some(Cow::Borrowed("hclua"))));
+    drop(guard);
+
+    let guard = lua.query_guard("n");
+    assert_eq!(guard.read_bytes(-1), None);
+    assert_eq!(guard.read_cow(-1), None);
+    drop(guard);
+    assert_eq!(lua.query::<i64, _>("n"), Some(12));
+}
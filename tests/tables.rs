@@ -0,0 +1,118 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+
+use hclua::{sys, Lua};
+
+#[test]
+fn tuples_are_nested_arrays() {
+    let mut lua = Lua::new();
+    lua.exec_string::<_, ()>("t = {{1, 'a'}, {2, 'b'}}")
+        .unwrap();
+    assert_eq!(
+        lua.query::<Vec<(u32, String)>, _>("t"),
+        Some(vec![(1, "a".into()), (2, "b".into())])
+    );
+
+    // each tuple is its own array, a tuple inside one is nested again
+    lua.set("u", vec![(1u32, ("a".to_string(), true))]);
+    assert_eq!(
+        lua.exec_string::<_, bool>("return u[1][1] == 1 and u[1][2][1] == 'a' and u[1][2][2]"),
+        Some(true)
+    );
+    assert_eq!(
+        lua.query::<Vec<(u32, (String, bool))>, _>("u"),
+        Some(vec![(1, ("a".into(), true))])
+    );
+    // the flat layout is not read as a nested tuple
+    lua.exec_string::<_, ()>("f = {{1, 'a', true}}").unwrap();
+    assert_eq!(lua.query::<Vec<(u32, (String, bool))>, _>("f"), None);
+
+    lua.set("m", HashMap::from([(1u32, (2u32, Some(3u32)))]));
+    assert_eq!(
+        lua.query::<HashMap<u32, (u32, Option<u32>)>, _>("m"),
+        Some(HashMap::from([(1, (2, Some(3)))]))
+    );
+}
+
+#[test]
+fn failed_reads_leave_the_stack_alone() {
+    let mut lua = Lua::new();
+    let state = lua.state();
+    let top = unsafe { sys::lua_gettop(state) };
+    lua.exec_string::<_, ()>("v = {{1, 'a', true}, {2, 'b', 'no'}} m = {a = 1, b = 'x'}")
+        .unwrap();
+    assert_eq!(lua.query::<Vec<(u32, String, bool)>, _>("v"), None);
+    assert_eq!(lua.query::<HashMap<String, u32>, _>("m"), None);
+    assert_eq!(lua.query::<BTreeMap<String, u32>, _>("m"), None);
+    assert_eq!(unsafe { sys::lua_gettop(state) }, top);
+}
+
+#[test]
+fn sequences() {
+    let mut lua = Lua::new();
+    lua.exec_string::<_, ()>("s = {1, 2, 3} holes = {1, nil, 3} named = {a = 1} mixed = {1, 'x'}")
+        .unwrap();
+    assert_eq!(lua.query::<Vec<u32>, _>("s"), Some(vec![1, 2, 3]));
+    assert_eq!(
+        lua.query::<VecDeque<u32>, _>("s"),
+        Some(VecDeque::from([1, 2, 3]))
+    );
+    assert_eq!(
+        lua.query::<Box<[u32]>, _>("s"),
+        Some(vec![1, 2, 3].into_boxed_slice())
+    );
+    assert_eq!(lua.query::<[u32; 3], _>("s"), Some([1, 2, 3]));
+    assert_eq!(lua.query::<[u32; 2], _>("s"), None);
+
+    // a hole is a nil element, only an `Option` takes it
+    assert_eq!(
+        lua.query::<Vec<Option<u32>>, _>("holes"),
+        Some(vec![Some(1), None, Some(3)])
+    );
+    assert_eq!(lua.query::<Vec<u32>, _>("holes"), None);
+
+    // only the sequence part is read, a table of names is an empty one
+    assert_eq!(lua.query::<Vec<u32>, _>("named"), Some(vec![]));
+    assert_eq!(lua.query::<Vec<u32>, _>("mixed"), None);
+    assert_eq!(lua.query::<VecDeque<u32>, _>("mixed"), None);
+    assert_eq!(lua.query::<Box<[u32]>, _>("mixed"), None);
+    lua.set("n", 1);
+    assert_eq!(lua.query::<Vec<u32>, _>("n"), None);
+}
+
+#[test]
+fn maps_and_sets() {
+    let mut lua = Lua::new();
+    lua.set(
+        "m",
+        HashMap::from([("a".to_string(), 1u32), ("b".into(), 2)]),
+    );
+    assert_eq!(
+        lua.query::<BTreeMap<String, u32>, _>("m"),
+        Some(BTreeMap::from([("a".into(), 1), ("b".into(), 2)]))
+    );
+    assert_eq!(lua.query::<HashMap<String, bool>, _>("m"), None);
+
+    lua.set("s", BTreeSet::from([1u32, 2]));
+    assert_eq!(
+        lua.exec_string::<_, bool>("return s[1] == true and s[2] == true"),
+        Some(true)
+    );
+    assert_eq!(
+        lua.query::<HashSet<u32>, _>("s"),
+        Some(HashSet::from([1, 2]))
+    );
+    // keys set to false are left out of a set, other values are not a set
+    lua.exec_string::<_, ()>("f = {[1] = true, [2] = false} x = {[1] = 'yes'}")
+        .unwrap();
+    assert_eq!(
+        lua.query::<BTreeSet<u32>, _>("f"),
+        Some(BTreeSet::from([1]))
+    );
+    assert_eq!(lua.query::<BTreeSet<u32>, _>("x"), None);
+    // a sequence is a map from its indices
+    lua.exec_string::<_, ()>("q = {'a', 'b'}").unwrap();
+    assert_eq!(
+        lua.query::<BTreeMap<u32, String>, _>("q"),
+        Some(BTreeMap::from([(1, "a".into()), (2, "b".into())]))
+    );
+}
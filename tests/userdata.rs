@@ -0,0 +1,376 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use hclua::{
+    push_lightuserdata, push_object, push_userdata, read_object, read_userdata, sys, Lua,
+    LuaObject, Owned, Shared, UserData,
+};
+
+#[derive(Default)]
+struct Counter {
+    count: i64,
+    drops: Rc<Cell<u32>>,
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        self.drops.set(self.drops.get() + 1);
+    }
+}
+
+impl UserData for Counter {
+    fn add_methods(methods: &mut hclua::UserDataMethods<Self>) {
+        methods.add_method("count", |c, ()| c.count);
+        methods.add_method_mut("take", |c, other: &mut Counter| {
+            c.count += other.count;
+            other.count = 0;
+        });
+        methods.add_method("same", |c, other: &Counter| c.count == other.count);
+        methods.add_method_mut("refuse", |c, other: &mut Counter| {
+            if other.count > c.count {
+                Ok(other.count)
+            } else {
+                Err("too small")
+            }
+        });
+    }
+}
+
+fn counter(drops: &Rc<Cell<u32>>) -> Counter {
+    Counter {
+        count: 7,
+        drops: drops.clone(),
+    }
+}
+
+#[test]
+fn layouts_are_not_mixed() {
+    let mut lua = Lua::new();
+    let state = lua.state();
+    let drops = Rc::new(Cell::new(0));
+    let top = unsafe { sys::lua_gettop(state) };
+
+    push_userdata(counter(&drops), state, |_| {});
+    push_object(counter(&drops), state);
+    assert_eq!(
+        read_userdata::<Counter>(state, -2).map(|c| c.count),
+        Some(7)
+    );
+    assert!(read_userdata::<Counter>(state, -1).is_none());
+    assert_eq!(read_object::<Counter>(state, -1).map(|c| c.count), Some(7));
+    assert!(read_object::<Counter>(state, -2).is_none());
+
+    unsafe { sys::lua_settop(state, top) };
+    drop(lua);
+    assert_eq!(drops.get(), 2);
+}
+
+#[test]
+fn raw_userdata_is_dropped_after_a_light_push() {
+    let mut lua = Lua::new();
+    let state = lua.state();
+    let drops = Rc::new(Cell::new(0));
+    let mut lent = counter(&drops);
+    let top = unsafe { sys::lua_gettop(state) };
+
+    // the metatable is created without `__gc` for the light userdata
+    push_lightuserdata(&mut lent, state, |_| {});
+    push_userdata(counter(&drops), state, |_| {});
+    unsafe {
+        sys::lua_settop(state, top);
+        sys::lua_gc(state, sys::LUA_GCCOLLECT, 0);
+    }
+    assert_eq!(drops.get(), 1);
+    drop(lent);
+}
+
+static PLAIN_DROPS: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Default)]
+struct Plain;
+
+impl Drop for Plain {
+    fn drop(&mut self) {
+        PLAIN_DROPS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl UserData for Plain {}
+
+#[test]
+fn lua_object_values_are_collected() {
+    let mut lua = Lua::new();
+    lua.openlibs();
+    LuaObject::<Plain>::new(lua.state(), "Plain").create();
+    lua.exec_string::<_, ()>("local p = Plain.new() p = nil collectgarbage()")
+        .unwrap();
+    assert_eq!(PLAIN_DROPS.load(Ordering::SeqCst), 1);
+    // a value pushed from Rust shares the class of the script ones
+    push_object(Plain, lua.state());
+    assert!(read_object::<Plain>(lua.state(), -1).is_some());
+    drop(lua);
+    assert_eq!(PLAIN_DROPS.load(Ordering::SeqCst), 2);
+}
+
+#[derive(Default)]
+struct Hero {
+    hp: i64,
+}
+
+impl UserData for Hero {}
+
+#[test]
+fn fields_are_kept_per_state() {
+    let mut lua = Lua::new();
+    let mut other = Lua::new();
+    for lua in [&mut lua, &mut other] {
+        lua.openlibs();
+        LuaObject::<Hero>::new(lua.state(), "Hero")
+            .create()
+            .add_method_get("hp", hclua::function1(|h: &mut Hero| h.hp + 1));
+    }
+    LuaObject::<Hero>::set_field_in(&mut lua, "hp");
+    assert!(LuaObject::<Hero>::is_field_in(&mut lua, "hp"));
+    assert!(!LuaObject::<Hero>::is_field_in(&mut other, "hp"));
+    // a field is read through its getter, elsewhere the same name stays a method
+    assert_eq!(lua.exec_string::<_, i64>("return Hero.new().hp"), Some(1));
+    assert_eq!(
+        other.exec_string::<_, i64>("return Hero.new():hp()"),
+        Some(1)
+    );
+}
+
+#[derive(Default)]
+struct Stats {
+    hp: i64,
+    level: i64,
+}
+
+impl UserData for Stats {
+    fn add_fields(fields: &mut hclua::UserDataFields<Self>) {
+        fields.add_field_get("hp", |s| s.hp);
+        fields.add_field_set("hp", |s, hp: i64| s.hp = hp);
+        fields.add_field_get("level", |s| s.level);
+    }
+
+    fn add_methods(methods: &mut hclua::UserDataMethods<Self>) {
+        methods.add_method("total", |s, ()| s.hp + s.level);
+    }
+}
+
+#[test]
+fn index_dispatch() {
+    let mut lua = Lua::new();
+    lua.openlibs();
+    lua.set("s", Owned(Stats { hp: 5, level: 2 }));
+    assert_eq!(
+        lua.exec_string::<_, i64>("s.hp = s.hp + 1 return s:total()"),
+        Some(8)
+    );
+    // unknown keys read as nil, writing them or a field without setter is an error
+    assert_eq!(
+        lua.exec_string::<_, bool>("return s.nothing == nil"),
+        Some(true)
+    );
+    assert_eq!(
+        lua.exec_string::<_, bool>("return pcall(function() s.nothing = 1 end)"),
+        Some(false)
+    );
+    assert_eq!(
+        lua.exec_string::<_, bool>("return pcall(function() s.level = 1 end)"),
+        Some(false)
+    );
+    assert_eq!(lua.exec_string::<_, i64>("return s.level"), Some(2));
+}
+
+#[test]
+fn scope_releases_lent_values() {
+    let mut lua = Lua::new();
+    let drops = Rc::new(Cell::new(0));
+    let mut world = counter(&drops);
+    let count = lua.scope(|s| {
+        s.lend_mut("world", &mut world);
+        s.exec_string::<_, i64>("return world:count()")
+    });
+    assert_eq!(count, Some(7));
+    // the script kept the global, using it is an error and not a dangling read
+    assert_eq!(lua.exec_string::<_, i64>("return world:count()"), None);
+    assert_eq!(drops.get(), 0);
+    drop(lua);
+    assert_eq!(drops.get(), 0);
+    drop(world);
+    assert_eq!(drops.get(), 1);
+}
+
+#[test]
+fn shared_values_are_read_only() {
+    let mut lua = Lua::new();
+    lua.openlibs();
+    let drops = Rc::new(Cell::new(0));
+    let shared = Rc::new(counter(&drops));
+    lua.set("c", Shared::from(shared.clone()));
+    assert_eq!(lua.exec_string::<_, i64>("return c:count()"), Some(7));
+    lua.set("other", Owned(counter(&drops)));
+    assert_eq!(
+        lua.exec_string::<_, bool>("return pcall(c.take, c, other)"),
+        Some(false)
+    );
+    // Rust dropping its side leaves the value to the script, and the other way around
+    drop(shared);
+    assert_eq!(lua.exec_string::<_, i64>("return c:count()"), Some(7));
+    lua.exec_string::<_, ()>("c = nil collectgarbage()")
+        .unwrap();
+    assert_eq!(drops.get(), 1);
+}
+
+#[test]
+fn locked_values_are_given_back() {
+    let mut lua = Lua::new();
+    lua.openlibs();
+    let drops = Rc::new(Cell::new(0));
+    let shared = Rc::new(RefCell::new(counter(&drops)));
+    lua.set("c", shared.clone());
+
+    let borrow = shared.borrow_mut();
+    assert_eq!(
+        lua.exec_string::<_, bool>("return pcall(c.count, c)"),
+        Some(false)
+    );
+    drop(borrow);
+    assert_eq!(lua.exec_string::<_, i64>("return c:count()"), Some(7));
+    shared.borrow_mut().count = 8;
+    assert_eq!(lua.exec_string::<_, i64>("return c:count()"), Some(8));
+}
+
+#[test]
+fn arguments_do_not_alias_self() {
+    let mut lua = Lua::new();
+    lua.openlibs();
+    let drops = Rc::new(Cell::new(0));
+    lua.set("a", Owned(counter(&drops)));
+    lua.set("b", Owned(counter(&drops)));
+    assert_eq!(
+        lua.exec_string::<_, i64>("a:take(b) return a:count() + b:count()"),
+        Some(14)
+    );
+    assert_eq!(
+        lua.exec_string::<_, bool>("return pcall(a.take, a, a)"),
+        Some(false)
+    );
+    // shared borrows of the same object are fine, and nothing stays borrowed after an error
+    assert_eq!(lua.exec_string::<_, bool>("return a:same(a)"), Some(true));
+    assert_eq!(
+        lua.exec_string::<_, i64>("a:take(b) return a:count()"),
+        Some(14)
+    );
+}
+
+#[test]
+fn errors_give_the_arguments_back() {
+    let mut lua = Lua::new();
+    lua.openlibs();
+    let drops = Rc::new(Cell::new(0));
+    lua.set("a", Owned(counter(&drops)));
+    lua.set("b", Owned(counter(&drops)));
+    lua.set(
+        "refuse",
+        hclua::function1(|c: &mut Counter| -> Result<i64, &str> {
+            Err(if c.count > 0 { "full" } else { "empty" })
+        }),
+    );
+    assert_eq!(
+        lua.exec_string::<_, bool>("return pcall(a.refuse, a, b)"),
+        Some(false)
+    );
+    assert_eq!(
+        lua.exec_string::<_, bool>("return pcall(refuse, b)"),
+        Some(false)
+    );
+    // neither `a` nor `b` stayed borrowed by the calls that failed
+    assert_eq!(
+        lua.exec_string::<_, i64>("a:take(b) b:take(a) return b:count()"),
+        Some(14)
+    );
+}
+
+#[derive(Default)]
+struct Unit {
+    hp: i64,
+}
+
+impl UserData for Unit {
+    fn add_methods(methods: &mut hclua::UserDataMethods<Self>) {
+        methods.add_method_mut("hit", |u, damage: i64| u.hp -= damage);
+        methods.add_method("hp", |u, ()| u.hp);
+    }
+}
+
+#[derive(Default)]
+struct Player {
+    unit: Unit,
+}
+
+impl UserData for Player {
+    fn add_methods(methods: &mut hclua::UserDataMethods<Self>) {
+        methods.inherit::<Unit>(|p| &mut p.unit);
+    }
+}
+
+#[test]
+fn inheritance_is_kept_per_state() {
+    for _ in 0..2 {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.register_userdata::<Unit>("Unit");
+        lua.register_userdata::<Player>("Player");
+        lua.set(
+            "p",
+            Owned(Player {
+                unit: Unit { hp: 10 },
+            }),
+        );
+        assert_eq!(lua.exec_string::<_, i64>("p:hit(3) return p:hp()"), Some(7));
+        assert_eq!(
+            lua.exec_string::<_, bool>("return p:is_a(Unit) and p:is_a('Player')"),
+            Some(true)
+        );
+        assert_eq!(lua.query::<&Unit, _>("p").map(|u| u.hp), Some(7));
+    }
+}
+
+struct Light(Rc<Cell<u32>>);
+
+impl Drop for Light {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() + 1);
+    }
+}
+
+impl UserData for Light {
+    const LIGHT: bool = true;
+
+    fn add_methods(methods: &mut hclua::UserDataMethods<Self>) {
+        methods.add_method("drops", |l, ()| l.0.get());
+    }
+}
+
+#[test]
+fn del_releases_once() {
+    let mut lua = Lua::new();
+    lua.openlibs();
+    let drops = Rc::new(Cell::new(0));
+    lua.set("l", Owned(Light(drops.clone())));
+    assert_eq!(lua.exec_string::<_, u32>("return l:drops()"), Some(0));
+    lua.exec_string::<_, ()>("l:del() l:del()").unwrap();
+    assert_eq!(drops.get(), 1);
+    // a released object raises instead of reading freed memory
+    assert_eq!(
+        lua.exec_string::<_, bool>("return pcall(l.drops, l)"),
+        Some(false)
+    );
+    lua.exec_string::<_, ()>("l = nil collectgarbage()")
+        .unwrap();
+    drop(lua);
+    assert_eq!(drops.get(), 1);
+}